@@ -0,0 +1,7 @@
+use std::env;
+
+fn main() {
+    // Expose the target triple so the `update` command knows which
+    // release artifact to download for the running binary
+    println!("cargo:rustc-env=WOZ_TARGET={}", env::var("TARGET").unwrap());
+}
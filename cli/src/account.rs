@@ -6,8 +6,49 @@ use rusoto_cognito_identity::*;
 use rusoto_cognito_idp::CognitoIdentityProvider;
 use rusoto_cognito_idp::*;
 
+use failure::Error;
+use failure::ResultExt;
+
 use crate::cache::FileCache;
 use crate::config::*;
+use crate::error::WozError;
+
+
+/// Cache key for the temporary AWS credentials of the logged in user
+pub const AWS_CREDENTIALS_KEY: &str = "aws_credentials";
+
+/// Cache key for the username of a signup that hasn't been verified
+pub const PENDING_SIGNUP_KEY: &str = "pending_signup";
+
+/// Cache keys holding the logged in user's session
+pub const SESSION_KEYS: [&str; 5] = [
+    "refresh_token",
+    "identity",
+    "user",
+    AWS_CREDENTIALS_KEY,
+    PENDING_SIGNUP_KEY,
+];
+
+/// Details about the user from the payload of an ID token
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct IdTokenClaims {
+    #[serde(rename = "cognito:username")]
+    pub username: String,
+    pub email: Option<String>,
+    pub sub: String,
+}
+
+/// Decodes the claims in an ID token. The signature is not verified
+/// so only use this for displaying information about the user.
+pub fn id_token_claims(id_token: &str) -> Result<IdTokenClaims, Error> {
+    let payload = id_token.split('.').nth(1)
+        .ok_or_else(|| format_err!("Malformed ID token"))?;
+    let bytes = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .context("Failed to decode ID token")?;
+    let claims = serde_json::from_slice(&bytes)
+        .context("Failed to parse ID token")?;
+    Ok(claims)
+}
 
 
 /// By default, CognitoIdentityProviderClient::new will attempt to use the aws
@@ -15,12 +56,12 @@ use crate::config::*;
 /// environment variables). If the user doesn't have any credentials,
 /// any calls with this client will fail. The client returned by this
 /// function uses anonymous credentials which prevents this issue.
-pub fn anonymous_identity_provider_client() -> CognitoIdentityProviderClient {
-    CognitoIdentityProviderClient::new_with(
-        HttpClient::new().expect("Failed to create HTTP client"),
+pub fn anonymous_identity_provider_client() -> Result<CognitoIdentityProviderClient, WozError> {
+    Ok(CognitoIdentityProviderClient::new_with(
+        http_client()?,
         StaticProvider::from(AwsCredentials::default()),
         Region::UsWest2
-    )
+    ))
 }
 
 /// By default, CognitoIdentityClient::new will attempt to use the aws
@@ -28,12 +69,17 @@ pub fn anonymous_identity_provider_client() -> CognitoIdentityProviderClient {
 /// environment variables). If the user doesn't have any credentials,
 /// any calls with this client will fail. The client returned by this
 /// function uses anonymous credentials which prevents this issue.
-pub fn anonymous_identity_client() -> CognitoIdentityClient {
-    CognitoIdentityClient::new_with(
-        HttpClient::new().expect("Failed to create HTTP client"),
+pub fn anonymous_identity_client() -> Result<CognitoIdentityClient, WozError> {
+    Ok(CognitoIdentityClient::new_with(
+        http_client()?,
         StaticProvider::from(AwsCredentials::default()),
         Region::UsWest2
-    )
+    ))
+}
+
+fn http_client() -> Result<HttpClient, WozError> {
+    HttpClient::new()
+        .map_err(|e| WozError::Network(format!("Failed to create HTTP client: {}", e)))
 }
 
 pub async fn signup(client: &CognitoIdentityProviderClient,
@@ -41,31 +87,154 @@ pub async fn signup(client: &CognitoIdentityProviderClient,
                     username: &str,
                     password: &str)
                     -> Result<SignUpResponse, RusotoError<SignUpError>> {
-    let mut request = SignUpRequest::default();
-    request.username = username.to_owned();
-    request.password = password.to_owned();
-    request.client_id = String::from(CLIENT_ID);
+    debug!("Signing up {} with email {}", username, email);
     let email = AttributeType {
         name: String::from("email"),
         value: Some(email.to_owned())
     };
-    request.user_attributes = Some(vec![email]);
+    let request = SignUpRequest {
+        client_id: String::from(CLIENT_ID),
+        username: username.to_owned(),
+        password: password.to_owned(),
+        user_attributes: Some(vec![email]),
+        ..Default::default()
+    };
     client.sign_up(request).await
 }
 
+/// Confirms the user's email address using the code sent to it
+pub async fn confirm_signup(client: &CognitoIdentityProviderClient, username: &str, code: &str)
+                            -> Result<ConfirmSignUpResponse, RusotoError<ConfirmSignUpError>> {
+    debug!("Confirming signup for {}", username);
+    let req = ConfirmSignUpRequest {
+        client_id: String::from(CLIENT_ID),
+        username: username.to_owned(),
+        confirmation_code: code.to_owned(),
+        ..Default::default()
+    };
+    client.confirm_sign_up(req).await
+}
+
+/// Sends a new verification code to the user's email address
+pub async fn resend_confirmation_code(client: &CognitoIdentityProviderClient, username: &str)
+                                      -> Result<ResendConfirmationCodeResponse, RusotoError<ResendConfirmationCodeError>> {
+    debug!("Requesting a new verification code for {}", username);
+    let req = ResendConfirmationCodeRequest {
+        client_id: String::from(CLIENT_ID),
+        username: username.to_owned(),
+        ..Default::default()
+    };
+    client.resend_confirmation_code(req).await
+}
+
+/// Sends a code to the user's email address for resetting their
+/// password
+pub async fn forgot_password(client: &CognitoIdentityProviderClient, username: &str)
+                             -> Result<ForgotPasswordResponse, RusotoError<ForgotPasswordError>> {
+    debug!("Requesting a password reset code for {}", username);
+    let req = ForgotPasswordRequest {
+        client_id: String::from(CLIENT_ID),
+        username: username.to_owned(),
+        ..Default::default()
+    };
+    client.forgot_password(req).await
+}
+
+/// Sets a new password using the code sent by `forgot_password`
+pub async fn confirm_forgot_password(client: &CognitoIdentityProviderClient,
+                                     username: &str,
+                                     code: &str,
+                                     password: &str)
+                                     -> Result<ConfirmForgotPasswordResponse, RusotoError<ConfirmForgotPasswordError>> {
+    debug!("Resetting the password for {}", username);
+    let req = ConfirmForgotPasswordRequest {
+        client_id: String::from(CLIENT_ID),
+        username: username.to_owned(),
+        confirmation_code: code.to_owned(),
+        password: password.to_owned(),
+        ..Default::default()
+    };
+    client.confirm_forgot_password(req).await
+}
+
+pub async fn change_password(client: &CognitoIdentityProviderClient,
+                             access_token: &str,
+                             previous_password: &str,
+                             proposed_password: &str)
+                             -> Result<ChangePasswordResponse, RusotoError<ChangePasswordError>> {
+    let req = ChangePasswordRequest {
+        access_token: access_token.to_owned(),
+        previous_password: previous_password.to_owned(),
+        proposed_password: proposed_password.to_owned(),
+    };
+    client.change_password(req).await
+}
+
+/// Changes the user's email address. A code is sent to the new
+/// address which needs to be passed to `verify_email`.
+pub async fn change_email(client: &CognitoIdentityProviderClient, access_token: &str, email: &str)
+                          -> Result<UpdateUserAttributesResponse, RusotoError<UpdateUserAttributesError>> {
+    let req = UpdateUserAttributesRequest {
+        access_token: access_token.to_owned(),
+        user_attributes: vec![AttributeType {
+            name: String::from("email"),
+            value: Some(email.to_owned())
+        }],
+        ..Default::default()
+    };
+    client.update_user_attributes(req).await
+}
+
+pub async fn verify_email(client: &CognitoIdentityProviderClient, access_token: &str, code: &str)
+                          -> Result<VerifyUserAttributeResponse, RusotoError<VerifyUserAttributeError>> {
+    let req = VerifyUserAttributeRequest {
+        access_token: access_token.to_owned(),
+        attribute_name: String::from("email"),
+        code: code.to_owned(),
+    };
+    client.verify_user_attribute(req).await
+}
+
+pub async fn delete_user(client: &CognitoIdentityProviderClient, access_token: &str)
+                         -> Result<(), RusotoError<DeleteUserError>> {
+    let req = DeleteUserRequest { access_token: access_token.to_owned() };
+    client.delete_user(req).await
+}
+
+/// Deletes the logged in user's account and the cached login once
+/// `confirm` returns true. Returns false if the user backed out.
+pub async fn delete_account(client: &CognitoIdentityProviderClient, cache: &FileCache,
+                            access_token: &str, confirm: impl FnOnce() -> Result<bool, WozError>)
+                            -> Result<bool, Error> {
+    if !confirm()? {
+        return Ok(false);
+    }
+
+    delete_user(client, access_token).await
+        .context("Failed to delete account")?;
+    for key in SESSION_KEYS.iter() {
+        cache.remove(key).context("Failed to remove cached login")?;
+    }
+    Ok(true)
+}
+
 pub async fn login(client: &CognitoIdentityProviderClient, username: &str, password: &str) -> Result<InitiateAuthResponse, RusotoError<InitiateAuthError>> {
-    let mut request = InitiateAuthRequest::default();
-    request.auth_flow = String::from("USER_PASSWORD_AUTH");
+    debug!("Logging in as {}", username);
     let mut auth_params = HashMap::new();
     auth_params.insert(String::from("USERNAME"), username.to_owned());
     auth_params.insert(String::from("PASSWORD"), password.to_owned());
-    request.client_id = String::from(CLIENT_ID);
-    request.auth_parameters = Some(auth_params);
+    let request = InitiateAuthRequest {
+        auth_flow: String::from("USER_PASSWORD_AUTH"),
+        client_id: String::from(CLIENT_ID),
+        auth_parameters: Some(auth_params),
+        ..Default::default()
+    };
     client.initiate_auth(request).await
 }
 
 pub async fn refresh_auth(client: &CognitoIdentityProviderClient, refresh_token: &str)
                           -> Result<InitiateAuthResponse, RusotoError<InitiateAuthError>> {
+    debug!("Refreshing tokens");
     let mut auth_params = HashMap::new();
     auth_params.insert(String::from("REFRESH_TOKEN"), refresh_token.to_string());
 
@@ -81,12 +250,15 @@ pub async fn refresh_auth(client: &CognitoIdentityProviderClient, refresh_token:
 
 pub async fn identity_id(client: &CognitoIdentityClient, id_token: &str)
                          -> Result<GetIdResponse, RusotoError<GetIdError>> {
+    debug!("Looking up the identity ID");
     let mut logins = HashMap::new();
     logins.insert(USER_POOL_URL.to_string(), id_token.to_owned());
 
-    let mut req = GetIdInput::default();
-    req.identity_pool_id = IDENTITY_POOL_ID.to_string();
-    req.logins = Some(logins);
+    let req = GetIdInput {
+        identity_pool_id: IDENTITY_POOL_ID.to_string(),
+        logins: Some(logins),
+        ..Default::default()
+    };
     client.get_id(req).await
 }
 
@@ -97,51 +269,61 @@ pub async fn setup(id_provider_client: &CognitoIdentityProviderClient,
                    id_client: &CognitoIdentityClient,
                    cache: &FileCache,
                    username: &str,
-                   password: &str) -> Result<(), InitiateAuthError> {
-    match login(&id_provider_client, username, password).await {
-        Ok(resp) => {
-            let auth_result = resp.authentication_result
-                .expect("No auth result");
+                   password: &str) -> Result<(), WozError> {
+    let auth_result = match login(&id_provider_client, username, password).await {
+        Ok(resp) => resp.authentication_result
+            .ok_or_else(|| WozError::Auth(String::from("Login did not return any tokens")))?,
+        Err(RusotoError::Service(InitiateAuthError::UserNotConfirmed(_))) => {
+            return Err(WozError::EmailNotVerified(
+                String::from("Your email address hasn't been verified, run 'woz verify' to verify it")
+            ));
+        },
+        Err(e) => {
+            return Err(WozError::from_rusoto(e, |msg| WozError::Auth(format!("Login failed: {}", msg))));
+        }
+    };
+    let cache_error = |e: Error| WozError::Auth(format!("Failed to cache login: {}", e));
 
-            // Store the refresh token
-            let refresh_token = auth_result.refresh_token
-                .expect("No access token found");
+    // Store the refresh token
+    let refresh_token = auth_result.refresh_token
+        .ok_or_else(|| WozError::Auth(String::from("No refresh token found")))?;
 
-            cache.set_encrypted("refresh_token", refresh_token.as_bytes().to_vec())
-                .expect("Failed to set refresh token in cache");
+    cache.set_encrypted("refresh_token", refresh_token.as_bytes().to_vec())
+        .map_err(cache_error)?;
 
-            // Store the identity ID
-            let id_token = auth_result.id_token
-                .expect("No ID token found");
+    // Store the identity ID
+    let id_token = auth_result.id_token
+        .ok_or_else(|| WozError::Auth(String::from("No ID token found")))?;
 
-            let identity_id = identity_id(&id_client, &id_token).await
-                .expect("Getting identity ID didn't work")
-                .identity_id.expect("No identity ID");
+    let identity_id = identity_id(&id_client, &id_token).await
+        .map_err(|e| WozError::from_rusoto(e, |msg| WozError::Auth(format!("Getting identity ID failed: {}", msg))))?
+        .identity_id
+        .ok_or_else(|| WozError::Auth(String::from("No identity ID found")))?;
 
-            cache.set("identity", identity_id.as_bytes().to_vec())
-                .expect("Failed to set identity ID in cache");
+    cache.set("identity", identity_id.as_bytes().to_vec())
+        .map_err(cache_error)?;
 
-            Ok(())
-        },
-        Err(error) => {
-            match error {
-                RusotoError::Service(e) => Err(e),
-                _ => panic!("Login failed: {}", error)
-            }
-        }
-    }
+    // Credentials cached for a previous login may belong to a
+    // different user
+    cache.remove(AWS_CREDENTIALS_KEY)
+        .map_err(cache_error)?;
+
+    Ok(())
 }
 
 pub type AWSCredentialsResponse = Result<GetCredentialsForIdentityResponse,
                                          RusotoError<GetCredentialsForIdentityError>>;
 pub async fn aws_credentials(client: &CognitoIdentityClient, identity_id: &str, id_token: &str)
                              ->  AWSCredentialsResponse {
+    debug!("Fetching AWS credentials for identity {}", identity_id);
     let mut logins = HashMap::new();
     logins.insert(USER_POOL_URL.to_string(), id_token.to_owned());
 
-    let mut req = GetCredentialsForIdentityInput::default();
-    req.identity_id = identity_id.to_owned();
-    req.logins = Some(logins);
+    let req = GetCredentialsForIdentityInput {
+        identity_id: identity_id.to_owned(),
+        logins: Some(logins),
+        ..Default::default()
+    };
     client.get_credentials_for_identity(req).await
 }
 
@@ -151,7 +333,7 @@ mod account_tests {
     use super::*;
     use rusoto_mock::{MockRequestDispatcher, MockResponseReader, ReadMockResponse};
 
-    fn mock_id_provider_client(resp: &String) -> CognitoIdentityProviderClient {
+    fn mock_id_provider_client(resp: &str) -> CognitoIdentityProviderClient {
         CognitoIdentityProviderClient::new_with(
             MockRequestDispatcher::default().with_body(resp),
             StaticProvider::from(AwsCredentials::default()),
@@ -159,7 +341,7 @@ mod account_tests {
         )
     }
 
-    fn mock_id_client(resp: &String) -> CognitoIdentityClient {
+    fn mock_id_client(resp: &str) -> CognitoIdentityClient {
         CognitoIdentityClient::new_with(
             MockRequestDispatcher::default().with_body(resp),
             StaticProvider::from(AwsCredentials::default()),
@@ -167,6 +349,20 @@ mod account_tests {
         )
     }
 
+    #[test]
+    fn test_id_token_claims() {
+        let payload = base64::encode_config(
+            r#"{"sub":"sub_123","cognito:username":"test_user","email":"test@example.com"}"#,
+            base64::URL_SAFE_NO_PAD
+        );
+        let claims = id_token_claims(&format!("header.{}.signature", payload)).unwrap();
+        assert_eq!(claims.username, "test_user");
+        assert_eq!(claims.email, Some(String::from("test@example.com")));
+        assert_eq!(claims.sub, "sub_123");
+
+        assert!(id_token_claims("not a token").is_err());
+    }
+
     #[tokio::test]
     async fn test_signup() {
         let resp = MockResponseReader::read_response(&"test_data", &"signup_response_success.json");
@@ -176,6 +372,84 @@ mod account_tests {
     }
 
 
+    #[tokio::test]
+    async fn test_confirm_signup() {
+        let resp = MockResponseReader::read_response("test_data", "empty_response_success.json");
+        let client = mock_id_provider_client(&resp);
+
+        confirm_signup(&client, "test_user", "123456").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resend_confirmation_code() {
+        let resp = MockResponseReader::read_response("test_data", "resend_confirmation_code_response_success.json");
+        let client = mock_id_provider_client(&resp);
+
+        let destination = resend_confirmation_code(&client, "test_user")
+            .await.unwrap()
+            .code_delivery_details.unwrap()
+            .destination.unwrap();
+
+        assert_eq!(destination, "t***@e***.com");
+    }
+
+    #[tokio::test]
+    async fn test_forgot_password() {
+        let resp = MockResponseReader::read_response("test_data", "forgot_password_response_success.json");
+        let client = mock_id_provider_client(&resp);
+
+        let destination = forgot_password(&client, "test_user")
+            .await.unwrap()
+            .code_delivery_details.unwrap()
+            .destination.unwrap();
+
+        assert_eq!(destination, "t***@e***.com");
+    }
+
+    #[tokio::test]
+    async fn test_confirm_forgot_password() {
+        let resp = MockResponseReader::read_response("test_data", "empty_response_success.json");
+        let client = mock_id_provider_client(&resp);
+
+        confirm_forgot_password(&client, "test_user", "123456", "new_password").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_change_password() {
+        let resp = MockResponseReader::read_response("test_data", "empty_response_success.json");
+        let client = mock_id_provider_client(&resp);
+
+        change_password(&client, "access_token_123", "old_password", "new_password").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_change_email() {
+        let resp = MockResponseReader::read_response("test_data", "change_email_response_success.json");
+        let client = mock_id_provider_client(&resp);
+
+        let details = change_email(&client, "access_token_123", "new@example.com")
+            .await.unwrap()
+            .code_delivery_details_list.unwrap();
+
+        assert_eq!(details[0].destination, Some(String::from("n***@e***.com")));
+    }
+
+    #[tokio::test]
+    async fn test_verify_email() {
+        let resp = MockResponseReader::read_response("test_data", "empty_response_success.json");
+        let client = mock_id_provider_client(&resp);
+
+        verify_email(&client, "access_token_123", "123456").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_user() {
+        let resp = MockResponseReader::read_response("test_data", "empty_response_success.json");
+        let client = mock_id_provider_client(&resp);
+
+        delete_user(&client, "access_token_123").await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_login() {
         let resp = MockResponseReader::read_response(&"test_data", &"login_response_success.json");
@@ -209,6 +483,27 @@ mod account_tests {
         assert_eq!(id, "id_123");
     }
 
+    #[tokio::test]
+    async fn delete_account_works() {
+        let encryption_key = [0; 32];
+        let cache = FileCache::new(encryption_key, std::env::temp_dir().join("woz-delete-account-test"));
+        cache.set("refresh_token", b"refresh_token_123".to_vec()).unwrap();
+
+        // Declining doesn't touch the account or the cached login
+        let client = CognitoIdentityProviderClient::new_with(
+            MockRequestDispatcher::default()
+                .with_request_checker(|_| panic!("Account was deleted without confirmation")),
+            StaticProvider::from(AwsCredentials::default()),
+            Region::UsWest2
+        );
+        assert!(!delete_account(&client, &cache, "access_token", || Ok(false)).await.unwrap());
+        assert_eq!(cache.get("refresh_token").unwrap(), "refresh_token_123");
+
+        let client = mock_id_provider_client("{}");
+        assert!(delete_account(&client, &cache, "access_token", || Ok(true)).await.unwrap());
+        assert!(cache.get("refresh_token").is_err());
+    }
+
     #[tokio::test]
     async fn test_setup() {
         let id_provider_resp = MockResponseReader::read_response(&"test_data", &"initiate_auth_response_success.json");
@@ -217,7 +512,7 @@ mod account_tests {
         let id_resp = MockResponseReader::read_response(&"test_data", &"get_id_response_success.json");
         let id_client = mock_id_client(&id_resp);
 
-        let encryption_key = FileCache::make_key(&"test_password", &"test_salt");
+        let encryption_key = [0; 32];
         let tmp_dir = std::env::temp_dir();
         let cache = FileCache::new(encryption_key, tmp_dir);
 
@@ -1,32 +1,59 @@
-use std::mem;
 use std::sync::{Arc, Mutex};
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
-use rusoto_s3::*;
-use rusoto_core::ByteStream;
+use std::time::Instant;
 use futures::stream;
 use futures::stream::StreamExt;
 
 use failure::Error;
 use failure::ResultExt;
-use flate2::Compression;
-use flate2::write::GzEncoder;
 
-use crate::config::S3_BUCKET_NAME;
-use crate::file_upload::FileUpload;
+use crate::file_upload::{FileUpload, sha256_hex};
 use crate::components::AppComponent;
+use crate::deploy::DeployTarget;
+use crate::deploy::manifest::{DeployManifest, relative_name};
+use crate::deploy::release::Releases;
 use crate::config::Environment;
+use crate::error::WozError;
 
 
+/// Counts of the files uploaded and skipped during a deploy
+#[derive(Debug, Default, PartialEq)]
+pub struct UploadSummary {
+    pub uploaded: usize,
+    pub uploaded_bytes: usize,
+    pub skipped: usize,
+    pub skipped_bytes: usize,
+    /// Names of the unchanged files relative to the key prefix
+    pub skipped_files: Vec<String>,
+}
+
+// Number of hex characters of the content hash used for the version
+const VERSION_LENGTH: usize = 12;
+
+enum Transfer {
+    Put(FileUpload),
+    /// Copies an unchanged file from the previous release
+    Copy(String, String),
+}
+
 /// Builds the application bundle, a collection of files to be
 /// uploaded. You can extend the app build by implementing the
 /// AppComponent trait and adding it to the build via
 /// the `component` method.
 pub struct AppBuilder<'a> {
     components: Vec<&'a dyn AppComponent>,
-    files: Vec<FileUpload>,
+    // Files produced by each component, in the same order as
+    // `components` so that a single component can be rebuilt
+    files: Vec<Vec<FileUpload>>,
+    // Files that depend on the rest of the bundle, see
+    // `AppComponent::bundle_files`
+    bundle_files: Vec<FileUpload>,
+    version: String,
+    include_git_commit: bool,
+    cargo_build: bool,
 }
 
 impl<'a> AppBuilder<'a> {
@@ -34,6 +61,10 @@ impl<'a> AppBuilder<'a> {
         Self {
             files: Vec::new(),
             components: Vec::new(),
+            bundle_files: Vec::new(),
+            version: String::new(),
+            include_git_commit: false,
+            cargo_build: true,
         }
     }
 
@@ -43,48 +74,256 @@ impl<'a> AppBuilder<'a> {
         self
     }
 
+    /// Appends the project's git commit to the build version
+    pub fn include_git_commit(&mut self, include: bool) -> &mut Self {
+        self.include_git_commit = include;
+        self
+    }
+
+    /// Runs `cargo build` before building the components. Turn it
+    /// off when a component compiles the project itself.
+    pub fn cargo_build(&mut self, enabled: bool) -> &mut Self {
+        self.cargo_build = enabled;
+        self
+    }
+
+    /// Returns the version of the last build
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
     /// Returns the size in bytes of the overall app file bundle
     pub fn size(&self) -> usize {
         let mut size = 0;
-        for FileUpload {bytes, ..} in self.files.iter() {
+        for FileUpload {bytes, ..} in self.files.iter().flatten().chain(self.bundle_files.iter()) {
             size += bytes.len();
         }
         size
     }
 
-    pub fn build(&mut self, project_path: &PathBuf,
-                 file_prefix: &str, env: &Environment) -> Result<(), Error> {
-        // Do a cargo build
+    /// Returns the files in the app bundle
+    pub fn files(&self) -> Vec<FileUpload> {
+        self.files.iter().flatten().chain(self.bundle_files.iter()).cloned().collect()
+    }
+
+    /// Returns the paths that affect the build: the project's
+    /// `Cargo.toml` and `src` directory plus any paths the components
+    /// depend on.
+    pub fn watch_paths(&self, project_path: &Path) -> Vec<PathBuf> {
+        let mut paths = Self::source_paths(project_path);
+        for cmpnt in self.components.iter() {
+            paths.extend(cmpnt.dependencies());
+        }
+        paths
+    }
+
+    fn source_paths(project_path: &Path) -> Vec<PathBuf> {
+        vec![project_path.join("Cargo.toml"), project_path.join("src")]
+    }
+
+    /// Resolves the path so it can be compared with paths reported
+    /// by the file watcher
+    fn canonical(path: &PathBuf) -> PathBuf {
+        fs::canonicalize(path).unwrap_or_else(|_| path.clone())
+    }
+
+    fn run_cargo_build(project_path: &PathBuf, env: &Environment) -> Result<(), Error> {
         let release_flag = match env {
             Environment::Production => " --release",
             _ => ""
         };
 
+        debug!("Running cargo build in {}", project_path.display());
+        let started = Instant::now();
         let mut build_proc = process::Command::new("sh")
             .current_dir(project_path)
             .arg("-c")
             .arg(format!("cargo build --target wasm32-unknown-unknown{}", release_flag))
             .stdout(process::Stdio::piped())
             .spawn()
-            .context("Failed to spawn build")?;
-        let exit_code = build_proc.wait().context("Failed to wait for build")?;
+            .context(WozError::Build(String::from("Failed to spawn build")))?;
+        let exit_code = build_proc.wait().context(WozError::Build(String::from("Failed to wait for build")))?;
         if !exit_code.success() {
-            return Err(format_err!("Build failed, please check output above."))
+            return Err(WozError::Build(String::from("Build failed, please check output above.")).into())
+        }
+        info!("Compiled with cargo in {:.2?}", started.elapsed());
+        Ok(())
+    }
+
+    pub fn build(&mut self, project_path: &PathBuf,
+                 file_prefix: &str, env: &Environment) -> Result<(), Error> {
+        if self.cargo_build {
+            Self::run_cargo_build(project_path, env)?;
         }
 
+        self.files.clear();
         for cmpnt in self.components.iter() {
-            let files = cmpnt.files(file_prefix).unwrap();
-            for f in files.into_iter() {
-                self.files.push(f);
-            };
+            self.files.push(cmpnt.files(file_prefix)?);
         };
 
+        self.bundle(project_path, file_prefix)
+    }
+
+    /// Returns a hash of the files so that the same build always has
+    /// the same version. The service worker cache is named after the
+    /// version, so it's only cleared when the app actually changes.
+    /// The prefix is left out so it doesn't depend on where the files
+    /// are written.
+    fn content_version(files: &[FileUpload], file_prefix: &str) -> String {
+        let mut hashes: Vec<(&str, String)> = files.iter()
+            .map(|f| (relative_name(&f.filename, file_prefix), f.hash()))
+            .collect();
+        hashes.sort();
+
+        let mut combined = String::new();
+        for (name, hash) in hashes.iter() {
+            combined.push_str(name);
+            combined.push_str(hash);
+        }
+        sha256_hex(combined.as_bytes())[..VERSION_LENGTH].to_owned()
+    }
+
+    fn git_commit(project_path: &PathBuf) -> Option<String> {
+        let output = process::Command::new("git")
+            .current_dir(project_path)
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8(output.stdout).ok().map(|c| c.trim().to_owned())
+    }
+
+    fn render_bundle_files(&self, file_prefix: &str, version: &str,
+                           files: &[FileUpload]) -> Result<Vec<FileUpload>, Error> {
+        let mut bundle_files = Vec::new();
+        for cmpnt in self.components.iter() {
+            bundle_files.extend(cmpnt.bundle_files(file_prefix, version, files)?);
+        }
+        Ok(bundle_files)
+    }
+
+    /// Sets the build version and adds the files that depend on the
+    /// whole bundle. The version can't hash the bundle files as they
+    /// include it, so they are rendered once with an empty version
+    /// for the hash, which covers everything else they're made from.
+    fn bundle(&mut self, project_path: &PathBuf, file_prefix: &str) -> Result<(), Error> {
+        let files: Vec<FileUpload> = self.files.iter().flatten().cloned().collect();
+        let mut hashed = files.clone();
+        hashed.extend(self.render_bundle_files(file_prefix, "", &files)?);
+        let mut version = Self::content_version(&hashed, file_prefix);
+        if self.include_git_commit {
+            match Self::git_commit(project_path) {
+                Some(commit) => version = format!("{}-{}", version, commit),
+                None => warn!("Unable to get the git commit, leaving it out of the version")
+            }
+        }
+
+        self.bundle_files = self.render_bundle_files(file_prefix, &version, &files)?;
+        self.version = version;
         Ok(())
     }
 
-    /// Upload the app file bundle to S3. It will be immediately
-    /// available on the public internet.
-    pub async fn upload(&self, client: S3Client) -> Result<(), Error> {
+    /// Rebuilds only the components affected by the `changed`
+    /// paths. A change to the project source runs cargo again and
+    /// rebuilds the components that use its output. Returns the
+    /// number of components that were rebuilt.
+    pub fn rebuild(&mut self, changed: &[PathBuf], project_path: &PathBuf,
+                   file_prefix: &str, env: &Environment) -> Result<usize, Error> {
+        let source_paths: Vec<PathBuf> = Self::source_paths(project_path)
+            .iter()
+            .map(Self::canonical)
+            .collect();
+        let source_changed = changed.iter()
+            .any(|p| source_paths.iter().any(|s| p.starts_with(s)));
+
+        if source_changed && self.cargo_build {
+            Self::run_cargo_build(project_path, env)?;
+        }
+
+        let mut rebuilt = 0;
+        for (i, cmpnt) in self.components.iter().enumerate() {
+            let deps: Vec<PathBuf> = cmpnt.dependencies()
+                .iter()
+                .map(Self::canonical)
+                .collect();
+            let affected = (source_changed && cmpnt.uses_build_output()) ||
+                changed.iter().any(|p| deps.iter().any(|d| p.starts_with(d)));
+
+            if affected {
+                self.files[i] = cmpnt.files(file_prefix)?;
+                rebuilt += 1;
+            }
+        };
+
+        if rebuilt > 0 {
+            self.bundle(project_path, file_prefix)?;
+        }
+        Ok(rebuilt)
+    }
+
+    /// Upload the app file bundle to the deploy target as a new
+    /// release under its own version prefix. The app's public URLs
+    /// are only switched to the release once every file is uploaded
+    /// so a failed deploy leaves the previous release live. Files
+    /// that are unchanged since the current release are copied
+    /// instead of uploaded again unless `force` is set.
+    pub async fn upload(&self, target: &dyn DeployTarget, key_prefix: &str,
+                        version: &str, force: bool) -> Result<UploadSummary, Error> {
+        let mut releases = Releases::fetch(target, key_prefix).await
+            .context(WozError::Upload(String::from("Failed to fetch releases")))?;
+        let release_prefix = Releases::prefix(key_prefix, version);
+        let files = self.files();
+        let manifest = DeployManifest::from_files(&files, key_prefix);
+        let mut summary = UploadSummary::default();
+
+        // Releases are immutable so an existing one only needs to be
+        // made live again
+        if releases.get(version).is_some() && !force {
+            summary.skipped = files.len();
+            summary.skipped_bytes = self.size();
+            summary.skipped_files = files.iter()
+                .map(|f| relative_name(&f.filename, key_prefix).to_owned())
+                .collect();
+            releases.activate(target, key_prefix, version).await?;
+            return Ok(summary);
+        }
+
+        let current_prefix = releases.current.as_ref()
+            .map(|v| Releases::prefix(key_prefix, v));
+        let previous = match &current_prefix {
+            Some(prefix) if !force => {
+                // A missing or broken manifest only means everything
+                // gets uploaded again
+                DeployManifest::fetch(target, prefix).await
+                    .unwrap_or_else(|e| {
+                        warn!("Unable to read previous deploy manifest, uploading all files: {}", e);
+                        DeployManifest::default()
+                    })
+            },
+            _ => DeployManifest::default()
+        };
+
+        let mut transfers = Vec::new();
+        for f in files.into_iter() {
+            let name = relative_name(&f.filename, key_prefix).to_owned();
+            let key = format!("{}/{}", release_prefix, name);
+            match &current_prefix {
+                Some(prefix) if manifest.unchanged(&previous, &name) => {
+                    summary.skipped += 1;
+                    summary.skipped_bytes += f.bytes.len();
+                    transfers.push(Transfer::Copy(format!("{}/{}", prefix, name), key));
+                    summary.skipped_files.push(name);
+                },
+                _ => {
+                    summary.uploaded += 1;
+                    summary.uploaded_bytes += f.bytes.len();
+                    transfers.push(Transfer::Put(FileUpload { filename: key, ..f }));
+                }
+            }
+        }
+
         // In order to get errors out of tokio they need to be share
         // the data in a thread safe way
         let failures = Arc::new(Mutex::new(0));
@@ -92,42 +331,25 @@ impl<'a> AppBuilder<'a> {
         // loop runs
         let fail_count = Arc::clone(&failures);
 
-        stream::iter(self.files.clone())
-            .for_each(|f| {
+        let started = Instant::now();
+        stream::iter(transfers)
+            .for_each(|transfer| {
                 // References the outer failures. This will get moved into
                 // the scope of the async task closure
                 let fails_ref = Arc::clone(&failures);
 
-                let FileUpload {filename, mimetype, bytes} = f;
-                let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
-                gzip.write_all(&bytes).expect("Failed to gzip encode bytes");
-                let compressed_bytes = gzip.finish().expect("Failed to gzip file");
-
-                let req = PutObjectRequest {
-                    bucket: String::from(S3_BUCKET_NAME),
-                    key: filename,
-                    body: Some(ByteStream::from(compressed_bytes)),
-                    content_type: Some(mimetype),
-                    content_encoding: Some(String::from("gzip")),
-                    ..Default::default()
-                };
-
-                // Need to borrow then move the client into async
-                // block so that it lives long enough
-                let borrowed_client = &client;
-
                 async move {
-                    let resp = borrowed_client.put_object(req).await;
+                    let started = Instant::now();
+                    let (resp, key) = match &transfer {
+                        Transfer::Put(f) => (target.put(f).await, &f.filename),
+                        Transfer::Copy(from, to) => (target.copy(from, to).await, to),
+                    };
                     match resp {
                         // Stream for_each expects futures to return ()
-                        Ok(_) => (),
+                        Ok(_) => debug!("Uploaded {} in {:.2?}", key, started.elapsed()),
                         Err(error) => {
-                            // Swap out the current count with our new
-                            // count of failures.
-                            let mut fails = *fails_ref.lock().unwrap();
-                            let mut new_fails = fails + 1;
-                            mem::swap(&mut fails, &mut new_fails);
-                            println!("File upload error: {}", error);
+                            *fails_ref.lock().unwrap() += 1;
+                            error!("File upload error for {}: {}", key, error);
                         }
                     }
                 }
@@ -135,17 +357,26 @@ impl<'a> AppBuilder<'a> {
             .await;
 
         if *fail_count.lock().unwrap() > 0 {
-            Err(format_err!("Failed to upload app to S3"))
-        } else {
-            Ok(())
+            return Err(WozError::Upload(String::from("Failed to upload app")).into());
         }
+        info!("Uploaded {} files and copied {} unchanged files in {:.2?}",
+              summary.uploaded, summary.skipped, started.elapsed());
+
+        target.put(&manifest.to_file(&release_prefix)?).await
+            .context(WozError::Upload(String::from("Failed to upload deploy manifest")))?;
 
+        // Only make the release live once every file made it
+        releases.add(version);
+        releases.activate(target, key_prefix, version).await
+            .context(WozError::Upload(String::from("Failed to activate release")))?;
+
+        Ok(summary)
     }
 
     /// Download the app bundle to disk
     pub fn download(&self) -> Result<(), Error> {
-        for FileUpload {filename, mimetype: _, bytes} in self.files.iter() {
-            println!("Downloading file {}", filename);
+        for FileUpload {filename, mimetype: _, bytes} in self.files.iter().flatten().chain(self.bundle_files.iter()) {
+            debug!("Downloading file {}", filename);
             let mut dir = PathBuf::from(filename);
             dir.pop();
             fs::create_dir_all(&dir).context("Failed to make directory").ok();
@@ -162,3 +393,97 @@ impl<'a> AppBuilder<'a> {
         Ok(())
     }
 }
+
+
+#[cfg(test)]
+mod builder_tests {
+    use super::*;
+    use std::env;
+    use crate::deploy::directory::DirectoryTarget;
+
+    fn file(filename: &str, contents: &[u8]) -> FileUpload {
+        FileUpload::new(String::from(filename), String::from("text/plain"), contents.to_vec())
+    }
+
+    #[test]
+    fn content_version_works() {
+        let files = vec![file("a/app.js", b"js"), file("a/app.wasm", b"wasm")];
+        let version = AppBuilder::content_version(&files, "a");
+        assert_eq!(version.len(), 12);
+
+        // Same files under another prefix and in another order
+        let moved = vec![file("b/app.wasm", b"wasm"), file("b/app.js", b"js")];
+        assert_eq!(AppBuilder::content_version(&moved, "b"), version);
+
+        let changed = vec![file("a/app.js", b"js"), file("a/app.wasm", b"changed")];
+        assert_ne!(AppBuilder::content_version(&changed, "a"), version);
+    }
+
+    // Renders a file with the version like the service worker
+    struct VersionedComponent(&'static str);
+
+    impl AppComponent for VersionedComponent {
+        fn files(&self, _file_prefix: &str) -> Result<Vec<FileUpload>, Error> {
+            Ok(vec![])
+        }
+
+        fn bundle_files(&self, file_prefix: &str, version: &str,
+                        _bundle: &[FileUpload]) -> Result<Vec<FileUpload>, Error> {
+            let contents = format!("{} {}", self.0, version);
+            Ok(vec![file(&format!("{}/sw.js", file_prefix), contents.as_bytes())])
+        }
+    }
+
+    #[test]
+    fn version_covers_bundle_files() {
+        let version = |component: &VersionedComponent| {
+            let mut app = AppBuilder::new();
+            app.component(component);
+            app.files = vec![vec![file("a/app.wasm", b"wasm")]];
+            app.bundle(&env::temp_dir(), "a").unwrap();
+            let sw = app.files().into_iter().find(|f| f.filename == "a/sw.js").unwrap();
+            assert_eq!(sw.bytes, format!("{} {}", component.0, app.version()).into_bytes());
+            app.version().to_owned()
+        };
+        assert_eq!(version(&VersionedComponent("cache-first")), version(&VersionedComponent("cache-first")));
+        assert_ne!(version(&VersionedComponent("cache-first")), version(&VersionedComponent("network-first")));
+    }
+
+    #[tokio::test]
+    async fn upload_skips_unchanged_files() {
+        let mut dir = env::temp_dir();
+        dir.push("woz-upload-test");
+        fs::remove_dir_all(&dir).ok();
+        let target = DirectoryTarget::new(dir.clone(), None);
+
+        // Activating a release copies these to the app's public URLs
+        let stable_names = ["index.html", "stable/app/index.html", "stable/app/manifest.json", "stable/app/sw.js"];
+        let stable: Vec<FileUpload> = stable_names.iter()
+            .map(|name| file(&format!("myapp/{}", name), b""))
+            .collect();
+
+        let mut app = AppBuilder::new();
+        app.files = vec![vec![file("myapp/a.txt", b"a"), file("myapp/b.txt", b"bb")], stable.clone()];
+        let summary = app.upload(&target, "myapp", "v1", false).await.unwrap();
+        assert_eq!(summary, UploadSummary {uploaded: 6, uploaded_bytes: 3, skipped: 0, skipped_bytes: 0,
+                                             skipped_files: vec![]});
+
+        app.files = vec![vec![file("myapp/a.txt", b"a"), file("myapp/b.txt", b"changed")], stable];
+        let summary = app.upload(&target, "myapp", "v2", false).await.unwrap();
+        let mut skipped_files = vec![String::from("a.txt")];
+        skipped_files.extend(stable_names.iter().map(|name| name.to_string()));
+        assert_eq!(summary, UploadSummary {uploaded: 1, uploaded_bytes: 7, skipped: 5, skipped_bytes: 1,
+                                             skipped_files});
+        assert_eq!(fs::read(dir.join("myapp/releases/v2/a.txt")).unwrap(), b"a");
+        assert_eq!(fs::read(dir.join("myapp/releases/v1/b.txt")).unwrap(), b"bb");
+
+        let summary = app.upload(&target, "myapp", "v3", true).await.unwrap();
+        assert_eq!(summary.uploaded, 6);
+        assert_eq!(summary.skipped, 0);
+
+        let releases = Releases::fetch(&target, "myapp").await.unwrap();
+        assert_eq!(releases.current, Some(String::from("v3")));
+        assert_eq!(releases.releases.len(), 3);
+        assert!(dir.join("myapp/app/sw.js").exists());
+    }
+}
@@ -1,7 +1,7 @@
-use std::io::{Read, Write};
-use std::fs::File;
+use std::io::{self, Read, Write};
 use std::fs;
-use std::path::PathBuf;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
 
 use ring::aead::*;
 use ring::rand::*;
@@ -12,6 +12,24 @@ use failure::Error;
 use failure::ResultExt;
 
 
+/// Encrypted values start with this header followed by the format
+/// version. Values written by older versions of woz have no header.
+const HEADER: &[u8; 4] = b"WOZC";
+const FORMAT_VERSION: u8 = 1;
+
+const KEY_ITERATIONS: u32 = 100_000;
+const LEGACY_KEY_ITERATIONS: u32 = 100;
+const KEY_SALT: &str = "woz-file-cache";
+
+/// Random secret generated for each installation that the key is
+/// derived from
+const SECRET_FILE_NAME: &str = ".secret";
+const SECRET_LEN: usize = 32;
+
+/// Environment variable with an optional passphrase that is combined
+/// with the installation secret to derive the key
+pub const PASSPHRASE_VAR: &str = "WOZ_PASSPHRASE";
+
 // TODO replace this with something useful. All it does is wrap a
 // Nonce, but since sealing/opening keys require you implement a
 // NonceSequence this is needed. Maybe generate the Nonce in the `new`
@@ -33,24 +51,77 @@ impl NonceSequence for OneNonceSequence {
 #[derive(Clone)]
 pub struct FileCache {
     key: [u8; 32],
+    legacy_key: Option<[u8; 32]>,
     path: PathBuf,
 }
 
+fn derive_key(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut key = [0; 32];
+    derive(
+        PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(iterations).unwrap(),
+        salt,
+        password,
+        &mut key
+    );
+    key
+}
+
+/// Creates a file only the current user can read and write
+fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    opts.open(path)
+}
+
 impl FileCache {
     pub fn new(key: [u8; 32], path: PathBuf) -> Self {
-        FileCache { key, path }
+        FileCache { key, legacy_key: None, path }
     }
 
-    pub fn make_key(password: &str, salt: &str) -> [u8; 32] {
-        let mut key = [0; 32];
-        derive(
-            PBKDF2_HMAC_SHA256,
-            std::num::NonZeroU32::new(100).unwrap(),
-            salt.as_bytes(),
-            &password.as_bytes()[..],
-            &mut key
-        );
-        key
+    /// Sets the key used by older versions of woz so their values
+    /// can be read and rewritten with `key`
+    pub fn legacy_key(mut self, key: [u8; 32]) -> Self {
+        self.legacy_key = Some(key);
+        self
+    }
+
+    /// Derives the key the way older versions of woz did
+    pub fn make_legacy_key(password: &str, salt: &str) -> [u8; 32] {
+        derive_key(password.as_bytes(), salt.as_bytes(), LEGACY_KEY_ITERATIONS)
+    }
+
+    /// Derives the key from the secret for this installation, which
+    /// is generated in `home_path` if it doesn't exist, and the
+    /// optional passphrase.
+    pub fn installation_key(home_path: &Path, passphrase: Option<&str>) -> Result<[u8; 32], Error> {
+        let path = home_path.join(SECRET_FILE_NAME);
+        let mut secret = match fs::read(&path) {
+            Ok(secret) => secret,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut secret = vec![0; SECRET_LEN];
+                SystemRandom::new()
+                    .fill(&mut secret)
+                    .map_err(|_| format_err!("Failed to generate a secret"))?;
+                fs::create_dir_all(home_path).context("Failed to make home directory")?;
+                create_private_file(&path)
+                    .and_then(|mut f| f.write_all(&secret))
+                    .context("Failed to write secret")?;
+                secret
+            },
+            Err(e) => return Err(e).context("Failed to read secret")?
+        };
+        if secret.len() != SECRET_LEN {
+            return Err(format_err!("Secret {} is corrupt, delete it and log in again", path.display()));
+        }
+
+        secret.extend(passphrase.unwrap_or("").as_bytes());
+        Ok(derive_key(&secret, KEY_SALT.as_bytes(), KEY_ITERATIONS))
     }
 
     fn encrypt(&self, content: Vec<u8>) -> Vec<u8> {
@@ -83,19 +154,20 @@ impl FileCache {
             &mut in_out
         ).expect("Failed to seal");
 
-        // Add in the nonce to the end so we can extract it later for
-        // decrypting
-        for i in nonce_value {
-            in_out.push(i);
-        }
-        in_out
+        // Add the header and nonce to the start so we can extract it
+        // later for decrypting
+        let mut out = HEADER.to_vec();
+        out.push(FORMAT_VERSION);
+        out.extend(nonce_value);
+        out.extend(in_out);
+        out
     }
 
-    fn decrypt(&self, nonce: Nonce, content: Vec<u8>) -> Vec<u8> {
+    fn decrypt(key: &[u8; 32], nonce: Nonce, content: Vec<u8>) -> Result<Vec<u8>, Error> {
         let mut in_out = content;
         // Opening key used to decrypt data
-        let o_key = UnboundKey::new(&CHACHA20_POLY1305, &self.key)
-            .expect("Failed to init decryption key");
+        let o_key = UnboundKey::new(&CHACHA20_POLY1305, key)
+            .map_err(|_| format_err!("Failed to init decryption key"))?;
         let mut opening_key = OpeningKey::new(
             o_key,
             OneNonceSequence::new(nonce)
@@ -108,24 +180,40 @@ impl FileCache {
         opening_key.open_in_place(
             additional_data,
             &mut in_out
-        ).expect("Failed to decrypt");
+        ).map_err(|_| format_err!("Failed to decrypt"))?;
         // Remove the extra padding from suffix
-        in_out[..in_out.len() - CHACHA20_POLY1305.tag_len()].to_vec()
+        Ok(in_out[..in_out.len() - CHACHA20_POLY1305.tag_len()].to_vec())
     }
 
-    fn extract_nonce(encrypted_content: Vec<u8>) -> (Nonce, Vec<u8>) {
+    /// Splits an encrypted value into the nonce and content
+    fn extract_nonce(encrypted_content: Vec<u8>) -> Result<(Nonce, Vec<u8>), Error> {
+        let start = HEADER.len() + 1;
+        if encrypted_content.len() < start + NONCE_LEN {
+            return Err(format_err!("Encrypted value is too short"));
+        }
+        let nonce = Nonce::try_assume_unique_for_key(&encrypted_content[start..start + NONCE_LEN])
+            .map_err(|_| format_err!("Failed to initialize nonce"))?;
+        Ok((nonce, encrypted_content[start + NONCE_LEN..].to_vec()))
+    }
+
+    /// Splits a value written by older versions of woz, which put the
+    /// nonce at the end, into the nonce and content
+    fn extract_legacy_nonce(encrypted_content: Vec<u8>) -> Result<(Nonce, Vec<u8>), Error> {
         let len = encrypted_content.len();
-        let content = encrypted_content[..len - 12].to_vec();
-        let nonce_value = encrypted_content[len - 12..len].to_vec();
-        let nonce = Nonce::try_assume_unique_for_key(&nonce_value)
-            .expect("Failed to initialize nonce");
-        (nonce, content)
+        if len < NONCE_LEN {
+            return Err(format_err!("Encrypted value is too short"));
+        }
+        let content = encrypted_content[..len - NONCE_LEN].to_vec();
+        let nonce = Nonce::try_assume_unique_for_key(&encrypted_content[len - NONCE_LEN..])
+            .map_err(|_| format_err!("Failed to initialize nonce"))?;
+        Ok((nonce, content))
     }
 
     fn write(home_path: &PathBuf, file_name: String, value: Vec<u8>) -> Result<(), Error> {
+        fs::create_dir_all(home_path).context("Failed to make home directory")?;
         let mut file_path = home_path.clone();
         file_path.push(file_name);
-        let mut f = File::create(&file_path).context("Failed to create file")?;
+        let mut f = create_private_file(&file_path).context("Failed to create file")?;
         Ok(f.write_all(&value).context("Failed to write file")?)
     }
 
@@ -151,15 +239,49 @@ impl FileCache {
         let mut buffer = vec![];
         f.read_to_end(&mut buffer).context("Failed to read encrypted file")?;
 
-        let (nonce, content) = Self::extract_nonce(buffer);
-        let out = String::from_utf8(self.decrypt(nonce, content))?;
-        Ok(out)
+        let value = if buffer.starts_with(HEADER) {
+            match buffer.get(HEADER.len()) {
+                Some(&FORMAT_VERSION) => (),
+                _ => return Err(format_err!(
+                    "Cached {} was written by a newer version of woz, please update", key
+                ))
+            };
+            let (nonce, content) = Self::extract_nonce(buffer)?;
+            Self::decrypt(&self.key, nonce, content)
+                .context(format!(
+                    "Failed to decrypt cached {}, check that {} is the one used to log in",
+                    key, PASSPHRASE_VAR
+                ))?
+        } else {
+            // Written by an older version of woz so rewrite it using
+            // the current key and format
+            let legacy_key = self.legacy_key
+                .ok_or_else(|| format_err!("Cached {} uses an unsupported format", key))?;
+            let (nonce, content) = Self::extract_legacy_nonce(buffer)?;
+            let value = Self::decrypt(&legacy_key, nonce, content)
+                .context(format!("Failed to decrypt cached {}", key))?;
+            self.set_encrypted(key, value.clone())
+                .context(format!("Failed to migrate cached {}", key))?;
+            value
+        };
+        Ok(String::from_utf8(value)?)
     }
 
     pub fn set_encrypted(&self, key: &str, value: Vec<u8>) -> Result<(), Error> {
         let encrypted_value = self.encrypt(value);
         Ok(self.set(key, encrypted_value)?)
     }
+
+    /// Removes the value, returns false if there wasn't one
+    pub fn remove(&self, key: &str) -> Result<bool, Error> {
+        let mut path = self.path.clone();
+        path.push(format!(".{}", key));
+        match fs::remove_file(path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into())
+        }
+    }
 }
 
 /// Note: files written during tests end up in the system temp
@@ -172,13 +294,14 @@ mod cache_tests {
 
 
     fn make_key() -> [u8; 32] {
-        FileCache::make_key("test password", "test salt")
+        derive_key(b"test password", b"test salt", KEY_ITERATIONS)
     }
 
     #[test]
-    fn make_key_works() {
-        let result = FileCache::make_key("test-password", "test-salt");
+    fn derive_key_works() {
+        let result = derive_key(b"test-password", b"test-salt", KEY_ITERATIONS);
         assert_eq!(result.len(), 32);
+        assert_ne!(result, derive_key(b"test-password", b"other-salt", KEY_ITERATIONS));
     }
 
     #[test]
@@ -196,7 +319,7 @@ mod cache_tests {
         let home_path = env::temp_dir();
         let cache = FileCache::new(key, home_path);
         let out = cache.encrypt(b"secret message".to_vec());
-        FileCache::extract_nonce(out);
+        FileCache::extract_nonce(out).unwrap();
     }
 
     #[test]
@@ -206,8 +329,8 @@ mod cache_tests {
         let cache = FileCache::new(key, home_path);
         let value = b"secret message".to_vec();
         let encrypted = cache.clone().encrypt(value.clone());
-        let (nonce, content) = FileCache::extract_nonce(encrypted);
-        let actual = cache.decrypt(nonce, content);
+        let (nonce, content) = FileCache::extract_nonce(encrypted).unwrap();
+        let actual = FileCache::decrypt(&key, nonce, content).unwrap();
         assert_eq!(value, actual);
     }
 
@@ -264,4 +387,59 @@ mod cache_tests {
         f.read_to_end(&mut buffer).unwrap();
         assert_ne!("test value".as_bytes().to_vec(), buffer);
     }
+
+    #[test]
+    fn remove_works() {
+        let key = make_key();
+        let home_path = env::temp_dir();
+        let cache = FileCache::new(key, home_path);
+        cache.set("test-remove-key", "test value".as_bytes().to_vec()).unwrap();
+        assert!(cache.remove("test-remove-key").unwrap());
+        assert!(cache.get("test-remove-key").is_err());
+        assert!(!cache.remove("test-remove-key").unwrap());
+    }
+
+    #[test]
+    fn installation_key_works() {
+        let mut home_path = env::temp_dir();
+        home_path.push("woz-installation-key-test");
+        fs::remove_dir_all(&home_path).ok();
+
+        let key = FileCache::installation_key(&home_path, None).unwrap();
+        assert_eq!(key, FileCache::installation_key(&home_path, None).unwrap());
+        assert_ne!(key, FileCache::installation_key(&home_path, Some("passphrase")).unwrap());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let secret = fs::metadata(home_path.join(SECRET_FILE_NAME)).unwrap();
+            assert_eq!(secret.permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn get_encrypted_migrates_legacy_values() {
+        let legacy_key = FileCache::make_legacy_key("test password", "test salt");
+        let mut home_path = env::temp_dir();
+        home_path.push("woz-legacy-cache-test");
+        fs::remove_dir_all(&home_path).ok();
+
+        // Older versions put the nonce at the end and had no header
+        let encrypted = FileCache::new(legacy_key, home_path.clone())
+            .encrypt(b"test value".to_vec());
+        let nonce_end = HEADER.len() + 1 + NONCE_LEN;
+        let mut legacy = encrypted[nonce_end..].to_vec();
+        legacy.extend(&encrypted[HEADER.len() + 1..nonce_end]);
+        fs::create_dir_all(&home_path).unwrap();
+        fs::write(home_path.join(".test-legacy-key"), legacy).unwrap();
+
+        let cache = FileCache::new(make_key(), home_path.clone());
+        assert!(cache.get_encrypted("test-legacy-key").is_err());
+
+        let cache = cache.legacy_key(legacy_key);
+        assert_eq!(cache.get_encrypted("test-legacy-key").unwrap(), "test value");
+        let migrated = fs::read(home_path.join(".test-legacy-key")).unwrap();
+        assert!(migrated.starts_with(HEADER));
+        assert_eq!(cache.get_encrypted("test-legacy-key").unwrap(), "test value");
+    }
 }
@@ -1,21 +1,24 @@
 use std::io::Read;
 use std::fs::File;
+use std::path::PathBuf;
 
 use failure::Error;
 use failure::ResultExt;
 
-use crate::config::{Config, DEFAULT_ICONS};
+use crate::config::{Config, DEFAULT_ICONS, ICON_SIZES};
 use super::AppComponent;
 use crate::file_upload::FileUpload;
+use crate::images::{self, ImageCache, SourceImage};
 
 
 pub struct IconComponent<'a> {
-    conf: &'a Config
+    conf: &'a Config,
+    cache: &'a ImageCache
 }
 
 impl<'a> IconComponent<'a> {
-    pub fn new(conf: &'a Config) -> Self {
-        Self { conf }
+    pub fn new(conf: &'a Config, cache: &'a ImageCache) -> Self {
+        Self { conf, cache }
     }
 }
 
@@ -38,6 +41,18 @@ impl<'a> AppComponent for IconComponent<'a> {
                     )
                 );
             }
+        } else if let Some(icon) = &self.conf.icon {
+            debug!("Generating icons from {}", icon.display());
+            let source = SourceImage::open(icon)?;
+            for (size, bytes) in images::icons(&source, &ICON_SIZES, self.cache)? {
+                uploads.push(
+                    FileUpload::new(
+                        format!("{}/app/img/icons/homescreen_{}.png", file_prefix, size),
+                        String::from("image/png"),
+                        bytes
+                    )
+                );
+            }
         } else {
             for (size, bytes) in DEFAULT_ICONS.iter() {
                 uploads.push(
@@ -52,4 +67,13 @@ impl<'a> AppComponent for IconComponent<'a> {
 
         Ok(uploads)
     }
+
+    fn dependencies(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.conf.icons.as_ref()
+            .map_or(Vec::new(), |paths| {
+                paths.to_vec().into_iter().map(|(_, path)| path.to_owned()).collect()
+            });
+        paths.extend(self.conf.icon.clone());
+        paths
+    }
 }
@@ -5,6 +5,7 @@ use handlebars::Handlebars;
 use crate::config::{Config, LANDING_PAGE_CSS};
 use super::AppComponent;
 use crate::file_upload::FileUpload;
+use crate::template::page_context;
 
 
 pub struct LandingPageComponent<'a> {
@@ -23,24 +24,26 @@ impl<'a> LandingPageComponent<'a> {
 
 impl<'a> AppComponent for LandingPageComponent<'a> {
     fn files(&self, file_prefix: &str) -> Result<Vec<FileUpload>, Error> {
+        Ok(vec![
+            FileUpload::new(format!("{}/main.css", file_prefix),
+                            String::from("text/css"),
+                            LANDING_PAGE_CSS.as_bytes().to_vec()),
+        ])
+    }
+
+    // Rendered with the rest of the bundle so the page can show the
+    // build version
+    fn bundle_files(&self, file_prefix: &str, version: &str,
+                    _bundle: &[FileUpload]) -> Result<Vec<FileUpload>, Error> {
         let index_template = self.templates.render(
             "landing_page_index",
-            &json!({
-                "name": self.conf.name,
-                "author": self.conf.author,
-                "description": self.conf.description,
-                "url": self.url,
-            })
+            &page_context(self.conf, self.url, version, json!({}))
         );
 
-        let uploads = vec![
+        Ok(vec![
             FileUpload::new(format!("{}/index.html", file_prefix),
                             String::from("text/html"),
                             index_template.context("Failed to render landing page index.html")?.into_bytes()),
-            FileUpload::new(format!("{}/main.css", file_prefix),
-                            String::from("text/css"),
-                            LANDING_PAGE_CSS.as_bytes().to_vec()),
-        ];
-        Ok(uploads)
+        ])
     }
 }
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use failure::Error;
 use crate::file_upload::FileUpload;
 
@@ -14,4 +16,28 @@ pub trait AppComponent {
     /// Returns a collection of file uploads to be added to be added
     /// to the application. Ordering does not matter.
     fn files(&self, file_prefix: &str) -> Result<Vec<FileUpload>, Error>;
+
+    /// Returns the paths the component reads files from. When
+    /// watching for changes, the component is rebuilt if any of these
+    /// paths change.
+    fn dependencies(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    /// Returns true if the component depends on the output of the
+    /// cargo build and should be rebuilt when the project source
+    /// changes.
+    fn uses_build_output(&self) -> bool {
+        false
+    }
+
+    /// Returns files that depend on the rest of the app bundle or the
+    /// build version. Called after every component's `files` with the
+    /// version and those files. It's also called with an empty
+    /// version to hash the result into the version, so the files must
+    /// only depend on these arguments and the component's config.
+    fn bundle_files(&self, _file_prefix: &str, _version: &str,
+                    _bundle: &[FileUpload]) -> Result<Vec<FileUpload>, Error> {
+        Ok(Vec::new())
+    }
 }
@@ -1,62 +1,363 @@
+use std::fs;
+use std::path::PathBuf;
+
 use failure::Error;
 use failure::ResultExt;
 use handlebars::Handlebars;
+use regex::Regex;
 
-use crate::config::Config;
+use crate::config::{Config, CacheStrategy, JsTarget, ServiceWorkerConfig,
+                    DEFAULT_ENTRY, DEFAULT_MOUNT_ID, START_ENTRY};
 use super::AppComponent;
+use crate::deploy::release::{Releases, STABLE_DIR};
+use crate::error::WozError;
 use crate::file_upload::FileUpload;
+use crate::template::page_context;
+
 
+// Name of the offline page in the app bundle
+const OFFLINE_PAGE: &str = "offline.html";
+
+lazy_static! {
+    // The entry is called from inline scripts so it must be a plain
+    // JavaScript identifier
+    static ref ENTRY_REGEX: Regex = Regex::new(r"^[A-Za-z_$][A-Za-z0-9_$]*$").unwrap();
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct PrecacheEntry {
+    /// URL relative to the service worker
+    url: String,
+    /// Hash of the file contents
+    revision: String,
+}
+
+/// Returns the files the service worker should cache when it is
+/// installed, which is everything under the app directory
+fn precache_manifest(file_prefix: &str, bundle: &[FileUpload]) -> Vec<PrecacheEntry> {
+    let app_prefix = format!("{}/app/", file_prefix);
+    let mut entries: Vec<PrecacheEntry> = bundle.iter()
+        .filter_map(|f| {
+            f.filename.strip_prefix(&app_prefix).map(|url| PrecacheEntry {
+                url: url.to_owned(),
+                revision: f.hash()
+            })
+        })
+        .collect();
+    entries.sort_by(|a, b| a.url.cmp(&b.url));
+    entries
+}
 
 pub struct PwaComponent<'a> {
     conf: &'a Config,
     url: &'a str,
-    version: &'a str,
-    templates: &'a Handlebars<'a>
+    templates: &'a Handlebars<'a>,
+    live_reload_path: Option<&'a str>
+}
+
+/// Returns the service worker settings that are rendered into sw.js.
+/// Route patterns are JavaScript regular expressions so they are
+/// passed on as is and checked by the browser. The offline page is
+/// linked relative to the service worker with `asset_path`.
+fn service_worker_settings(conf: &ServiceWorkerConfig, asset_path: &str) -> serde_json::Value {
+    let mut routes = Vec::new();
+    for route in conf.routes.iter() {
+        routes.push(json!({
+            "pattern": route.pattern,
+            "strategy": route.strategy,
+            "maxAgeSeconds": route.max_age_seconds.or(conf.max_age_seconds),
+            "maxEntries": route.max_entries.or(conf.max_entries)
+        }));
+    }
+
+    json!({
+        "routes": routes,
+        "defaultRoute": {
+            "strategy": conf.default_strategy.unwrap_or(CacheStrategy::CacheFirst),
+            "maxAgeSeconds": conf.max_age_seconds,
+            "maxEntries": conf.max_entries
+        },
+        "offlineUrl": conf.offline_page.as_ref().map(|_| format!("{}{}", asset_path, OFFLINE_PAGE))
+    })
 }
 
 impl<'a> PwaComponent<'a> {
     pub fn new(conf: &'a Config,
                url: &'a str,
-               templates: &'a Handlebars,
-               version: &'a str) -> Self {
-        Self { conf, url, templates, version }
+               templates: &'a Handlebars) -> Self {
+        Self { conf, url, templates, live_reload_path: None }
     }
-}
 
-impl<'a> AppComponent for PwaComponent<'a> {
-    fn files(&self, file_prefix: &str) -> Result<Vec<FileUpload>, Error> {
-        let index_template = self.templates.render("app_index", &json!({
-            "name": self.conf.name,
-            "author": self.conf.author,
-            "description": self.conf.description,
-            "url": self.url,
+    /// Adds a snippet to index.html that reloads the page when an
+    /// event is received from the server at `path`
+    pub fn live_reload(mut self, path: &'a str) -> Self {
+        self.live_reload_path = Some(path);
+        self
+    }
+
+    /// Returns the entry function and mount element id, which are
+    /// written into inline scripts and markup so they are checked
+    /// here. The wasm component checks the entry is exported and that
+    /// the lib supports the js target.
+    fn entry_and_mount_id(&self) -> Result<(&str, &str), Error> {
+        let entry = self.conf.entry.as_deref().unwrap_or(DEFAULT_ENTRY);
+        if !ENTRY_REGEX.is_match(entry) {
+            return Err(WozError::Config(
+                format!("Invalid entry {:?}, it needs to be the name of an exported function", entry)
+            ).into());
+        }
+        let mount_id = self.conf.mount_id.as_deref().unwrap_or(DEFAULT_MOUNT_ID);
+        if mount_id.is_empty() || mount_id.contains(|c: char| c.is_whitespace() || c == '"') {
+            return Err(WozError::Config(
+                format!("Invalid mount_id {:?}, it needs to be a valid element id", mount_id)
+            ).into());
+        }
+        Ok((entry, mount_id))
+    }
+
+    /// Renders index.html and manifest.json into the app directory
+    /// `dir`. Links to the rest of the app bundle start with
+    /// `asset_path`.
+    fn app_shell(&self, dir: &str, version: &str, asset_path: &str) -> Result<Vec<FileUpload>, Error> {
+        let (entry, mount_id) = self.entry_and_mount_id()?;
+        let self_starting = self.conf.lib.unwrap_or_default().is_self_starting();
+
+        let index_context = page_context(self.conf, self.url, version, json!({
+            "asset_path": asset_path,
             "manifest_path": "./manifest.json",
-            "app_js_path": "./app.js",
+            "app_js_path": format!("{}app.js", asset_path),
             "sw_js_path": "./sw.js",
-            "wasm_path": "./app.wasm",
-            "bg_color": self.conf.bg_color
+            "wasm_path": format!("{}app.wasm", asset_path),
+            "bg_color": self.conf.bg_color,
+            "live_reload_path": self.live_reload_path,
+            "js_module": self.conf.js_target.unwrap_or_default() == JsTarget::Web,
+            "self_starting": self_starting,
+            "entry": if entry == START_ENTRY || self_starting { None } else { Some(entry) },
+            "mount_id": mount_id
         }));
-        let manifest_template = self.templates.render("manifest", &json!({
-            "name": self.conf.name,
+        let index_template = self.templates.render("app_index", &index_context);
+        let manifest_context = page_context(self.conf, self.url, version, json!({
             "short_name": self.conf.short_name,
             "bg_color": self.conf.bg_color,
-            "description": self.conf.description
-        }));
-        let service_worker_template = self.templates.render("sw.js", &json!({
-            "version": self.version
+            "asset_path": asset_path
         }));
+        let manifest_template = self.templates.render("manifest", &manifest_context);
 
-        let uploads = vec![
-            FileUpload::new(format!("{}/app/index.html", file_prefix),
+        Ok(vec![
+            FileUpload::new(format!("{}/index.html", dir),
                             String::from("text/html"),
                             index_template.context("Failed to render index.html")?.into_bytes()),
-            FileUpload::new(format!("{}/app/manifest.json", file_prefix),
+            FileUpload::new(format!("{}/manifest.json", dir),
                             String::from("application/manifest+json"),
                             manifest_template.context("Failed to render manifest.json")?.into_bytes()),
-            FileUpload::new(format!("{}/app/sw.js", file_prefix),
-                            String::from("application/javascript"),
-                            service_worker_template.context("Failed to render sw.js")?.into_bytes()),
-        ];
+        ])
+    }
+
+    /// Renders sw.js into the app directory `dir`
+    fn service_worker(&self, dir: &str, version: &str, precache: &[PrecacheEntry],
+                      asset_path: &str) -> Result<FileUpload, Error> {
+        let precache_manifest = serde_json::to_string(precache)
+            .context("Failed to serialize precache manifest")?;
+        let default_conf = ServiceWorkerConfig::default();
+        let sw_conf = self.conf.service_worker.as_ref().unwrap_or(&default_conf);
+        let settings = service_worker_settings(sw_conf, asset_path).to_string();
+
+        let context = page_context(self.conf, self.url, version, json!({
+            "precache_manifest": precache_manifest,
+            "settings": settings
+        }));
+        let service_worker_template = self.templates.render("sw.js", &context);
+        Ok(FileUpload::new(format!("{}/sw.js", dir),
+                           String::from("application/javascript"),
+                           service_worker_template.context("Failed to render sw.js")?.into_bytes()))
+    }
+}
+
+impl<'a> AppComponent for PwaComponent<'a> {
+    fn files(&self, file_prefix: &str) -> Result<Vec<FileUpload>, Error> {
+        // Fail before building the rest of the app, the pages that
+        // use them are rendered with the bundle
+        self.entry_and_mount_id()?;
+
+        let mut uploads = Vec::new();
+
+        let offline_page = self.conf.service_worker.as_ref()
+            .and_then(|sw| sw.offline_page.as_ref());
+        if let Some(path) = offline_page {
+            let bytes = fs::read(path)
+                .context(format!("Failed to read offline page {}", path.display()))?;
+            uploads.push(FileUpload::new(format!("{}/app/{}", file_prefix, OFFLINE_PAGE),
+                                         String::from("text/html"),
+                                         bytes));
+        }
+        Ok(uploads)
+    }
+
+    fn dependencies(&self) -> Vec<PathBuf> {
+        self.conf.service_worker.as_ref()
+            .and_then(|sw| sw.offline_page.clone())
+            .into_iter()
+            .collect()
+    }
+
+    fn bundle_files(&self, file_prefix: &str, version: &str,
+                    bundle: &[FileUpload]) -> Result<Vec<FileUpload>, Error> {
+        // The app shell is rendered here so it can use the version
+        let app_dir = format!("{}/app", file_prefix);
+        let mut uploads = self.app_shell(&app_dir, version, "./")?;
+        let mut precached = bundle.to_vec();
+        precached.extend(uploads.iter().cloned());
+        debug!("Precaching {} files in the service worker", precached.len());
+        let precache = precache_manifest(file_prefix, &precached);
+        uploads.push(self.service_worker(&app_dir, version, &precache, "./")?);
+
+        // The app shell served from the app's public URLs once the
+        // release is live. It loads everything else from the release
+        // and its service worker precaches those files.
+        let stable_prefix = format!("{}/{}", file_prefix, STABLE_DIR);
+        let asset_path = format!("../{}/app/", Releases::path(version));
+        let shell = self.app_shell(&format!("{}/app", stable_prefix), version, &asset_path)?;
+        let shell_precache = precache_manifest(&stable_prefix, &shell);
+        let mut stable_precache: Vec<PrecacheEntry> = precache.into_iter()
+            .filter(|e| !shell_precache.iter().any(|s| s.url == e.url))
+            .map(|e| PrecacheEntry { url: format!("{}{}", asset_path, e.url), ..e })
+            .collect();
+        stable_precache.extend(shell_precache);
+        stable_precache.sort_by(|a, b| a.url.cmp(&b.url));
+
+        uploads.push(self.service_worker(&format!("{}/app", stable_prefix), version,
+                                         &stable_precache, &asset_path)?);
+        uploads.extend(shell);
         Ok(uploads)
     }
 }
+
+
+#[cfg(test)]
+mod pwa_tests {
+    use super::*;
+    use crate::builder::AppBuilder;
+    use crate::config::Environment;
+
+    #[test]
+    fn precache_manifest_works() {
+        let bundle = vec![
+            FileUpload::new(String::from("myapp/index.html"), String::from("text/html"), b"landing".to_vec()),
+            FileUpload::new(String::from("myapp/app/index.html"), String::from("text/html"), b"app".to_vec()),
+            FileUpload::new(String::from("myapp/app/app.wasm"), String::from("application/wasm"), b"wasm".to_vec()),
+        ];
+        let entries = precache_manifest("myapp", &bundle);
+        assert_eq!(entries, vec![
+            PrecacheEntry { url: String::from("app.wasm"), revision: bundle[2].hash() },
+            PrecacheEntry { url: String::from("index.html"), revision: bundle[1].hash() },
+        ]);
+    }
+
+    #[test]
+    fn stable_app_shell_works() {
+        let conf = Config::default();
+        let templates = crate::template::load_templates().unwrap();
+        let pwa = PwaComponent::new(&conf, "https://example.com/myapp/index.html", &templates);
+        let mut bundle = pwa.files("myapp").unwrap();
+        bundle.push(FileUpload::new(String::from("myapp/app/app.wasm"),
+                                    String::from("application/wasm"),
+                                    b"wasm".to_vec()));
+        let files = pwa.bundle_files("myapp", "abc", &bundle).unwrap();
+        let read = |name: &str| {
+            let file = files.iter().find(|f| f.filename == name).unwrap();
+            String::from_utf8(file.bytes.clone()).unwrap()
+        };
+
+        // Once live the manifest is served from myapp/app/ and links
+        // resolve relative to it
+        let manifest: serde_json::Value = serde_json::from_str(&read("myapp/stable/app/manifest.json")).unwrap();
+        let start_url = format!("myapp/app/{}", manifest["start_url"].as_str().unwrap());
+        assert_eq!(start_url, "myapp/app/index.html");
+        assert!(!start_url.contains("releases/"));
+        assert_eq!(manifest["scope"], "./");
+        assert_eq!(manifest["icons"][0]["src"], "../releases/abc/app/img/icons/homescreen_48x48.png");
+
+        let shell = read("myapp/stable/app/index.html");
+        assert!(shell.contains("<script src=\"../releases/abc/app/app.js\"></script>"));
+        assert!(shell.contains("wasm_bindgen('../releases/abc/app/app.wasm')"));
+        assert!(shell.contains("navigator.serviceWorker.register('./sw.js', {scope: './'})"));
+
+        let sw = read("myapp/stable/app/sw.js");
+        assert!(sw.contains("\"url\":\"../releases/abc/app/app.wasm\""));
+        assert!(sw.contains("\"url\":\"index.html\""));
+        assert!(!sw.contains("../releases/abc/app/index.html"));
+
+        // The release keeps its own copy for the release's URL
+        assert!(read("myapp/app/sw.js").contains("\"url\":\"app.wasm\""));
+    }
+
+    #[test]
+    fn invalid_entry_is_rejected() {
+        let templates = crate::template::load_templates().unwrap();
+        let mut conf = Config { entry: Some(String::from("$run_app2")), ..Default::default() };
+        let pwa = PwaComponent::new(&conf, "https://example.com/myapp/index.html", &templates);
+        assert!(pwa.files("myapp").is_ok());
+
+        for entry in &["run(); alert(1)", "2run", "app.run", ""] {
+            conf.entry = Some(entry.to_string());
+            let pwa = PwaComponent::new(&conf, "https://example.com/myapp/index.html", &templates);
+            let err = pwa.files("myapp").err().expect(entry);
+            assert!(matches!(err.downcast_ref::<WozError>(), Some(WozError::Config(_))), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn service_worker_config_changes_version() {
+        let templates = crate::template::load_templates().unwrap();
+        let version = |toml: &str| {
+            let conf: Config = toml::from_str(toml).unwrap();
+            let pwa = PwaComponent::new(&conf, "https://example.com/myapp/index.html", &templates);
+            let mut app = AppBuilder::new();
+            app.component(&pwa).cargo_build(false);
+            app.build(&std::env::temp_dir(), "myapp", &Environment::Development).unwrap();
+            app.version().to_owned()
+        };
+
+        // Nothing else in the app changes so a deploy would otherwise
+        // reuse the previous release and its service worker
+        let default = version("");
+        assert_eq!(version("[service_worker]"), default);
+        assert_ne!(version("[service_worker]\ndefault_strategy=\"network-first\""), default);
+        assert_ne!(version("[service_worker]\nmax_entries=10"), default);
+        assert_ne!(version("[[service_worker.routes]]\npattern=\"/api/\"\nstrategy=\"network-only\""), default);
+    }
+
+    #[test]
+    fn service_worker_settings_works() {
+        let conf: Config = toml::from_str("
+[service_worker]
+offline_page=\"offline.html\"
+max_age_seconds=60
+
+[[service_worker.routes]]
+pattern=\"/api/\"
+strategy=\"network-only\"
+
+[[service_worker.routes]]
+pattern=\"\\\\.png$\"
+strategy=\"stale-while-revalidate\"
+max_age_seconds=3600
+").unwrap();
+        let settings = service_worker_settings(conf.service_worker.as_ref().unwrap(), "./");
+        assert_eq!(settings["routes"][0]["strategy"], "network-only");
+        assert_eq!(settings["routes"][0]["maxAgeSeconds"], 60);
+        assert_eq!(settings["routes"][1]["maxAgeSeconds"], 3600);
+        assert_eq!(settings["defaultRoute"]["strategy"], "cache-first");
+        assert_eq!(settings["offlineUrl"], "./offline.html");
+
+        // Patterns are JavaScript regular expressions, including
+        // syntax Rust's regex crate doesn't support
+        let conf: Config = toml::from_str("
+[[service_worker.routes]]
+pattern=\"^https://api\\\\.example\\\\.com/(?!auth/)\"
+strategy=\"network-first\"
+").unwrap();
+        let settings = service_worker_settings(conf.service_worker.as_ref().unwrap(), "./");
+        assert_eq!(settings["routes"][0]["pattern"], "^https://api\\.example\\.com/(?!auth/)");
+    }
+}
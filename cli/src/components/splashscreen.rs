@@ -1,21 +1,24 @@
 use std::io::Read;
 use std::fs::File;
+use std::path::PathBuf;
 
 use failure::Error;
 use failure::ResultExt;
 
-use crate::config::{Config, DEFAULT_SPLASHSCREENS};
+use crate::config::{Config, DEFAULT_SPLASHSCREENS, SPLASHSCREEN_SIZES};
 use super::AppComponent;
 use crate::file_upload::FileUpload;
+use crate::images::{self, ImageCache, SourceImage};
 
 
 pub struct SplashscreenComponent<'a> {
-    conf: &'a Config
+    conf: &'a Config,
+    cache: &'a ImageCache
 }
 
 impl<'a> SplashscreenComponent<'a> {
-    pub fn new(conf: &'a Config) -> Self {
-        Self { conf }
+    pub fn new(conf: &'a Config, cache: &'a ImageCache) -> Self {
+        Self { conf, cache }
     }
 }
 
@@ -38,6 +41,25 @@ impl<'a> AppComponent for SplashscreenComponent<'a> {
                     )
                 );
             };
+        } else if let Some(icon) = &self.conf.icon {
+            debug!("Generating splashscreens from {}", icon.display());
+            let source = SourceImage::open(icon)?;
+            let bg_color = self.conf.bg_color.as_deref().unwrap_or("#ffffff");
+            let generated = images::splashscreens(
+                &source,
+                &SPLASHSCREEN_SIZES,
+                bg_color,
+                self.cache
+            )?;
+            for (device, bytes) in generated {
+                uploads.push(
+                    FileUpload::new(
+                        format!("{}/app/img/splashscreens/{}.png", file_prefix, device),
+                        String::from("image/png"),
+                        bytes
+                    )
+                );
+            };
         } else {
             for (device, bytes) in DEFAULT_SPLASHSCREENS.iter() {
                 uploads.push(
@@ -52,4 +74,13 @@ impl<'a> AppComponent for SplashscreenComponent<'a> {
 
         Ok(uploads)
     }
+
+    fn dependencies(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.conf.splashscreens.as_ref()
+            .map_or(Vec::new(), |paths| {
+                paths.to_vec().into_iter().map(|(_, path)| path.to_owned()).collect()
+            });
+        paths.extend(self.conf.icon.clone());
+        paths
+    }
 }
@@ -1,69 +1,394 @@
-use std::io::Read;
+use std::io;
 use std::fs;
-use std::fs::File;
 use std::process;
-use std::path::PathBuf;
+use std::time::Instant;
+use std::path::{Path, PathBuf};
 
 use failure::Error;
 use failure::ResultExt;
+use handlebars::Handlebars;
+use crate::config::{Config, Environment, JsTarget, Lib, DEFAULT_ENTRY, START_ENTRY};
+use crate::error::WozError;
 use crate::file_upload::FileUpload;
+use crate::output;
+use crate::wasm_binary;
+use crate::wasm_opt;
 use super::AppComponent;
 
 
+const WASM_BINDGEN: &str = "wasm-bindgen";
+
+/// Returns the versions of the package in the contents of a
+/// `Cargo.lock` file
+fn locked_versions(lockfile: &str, package: &str) -> Result<Vec<String>, Error> {
+    let lock: toml::Value = toml::from_str(lockfile).context("Failed to parse Cargo.lock")?;
+    let packages = lock.get("package").and_then(|p| p.as_array());
+    let mut versions: Vec<String> = packages.into_iter()
+        .flatten()
+        .filter(|p| p.get("name").and_then(|n| n.as_str()) == Some(package))
+        .filter_map(|p| p.get("version").and_then(|v| v.as_str()).map(String::from))
+        .collect();
+    versions.dedup();
+    Ok(versions)
+}
+
+/// Returns the `Cargo.lock` for the project, which is in a parent
+/// directory when the project is part of a workspace
+fn find_lockfile(project_path: &Path) -> Option<PathBuf> {
+    project_path.ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.is_file())
+}
+
+/// Returns the version from the output of `wasm-bindgen --version`
+/// such as `wasm-bindgen 0.2.100`
+fn parse_cli_version(output: &str) -> Option<String> {
+    output.split_whitespace().nth(1).map(String::from)
+}
+
+fn install_command(version: &str) -> String {
+    format!("cargo install -f wasm-bindgen-cli --version {}", version)
+}
+
+/// Checks that the installed wasm-bindgen cli is the version the
+/// project depends on. They need to match exactly, otherwise the
+/// generated bindings fail in confusing ways at runtime.
+fn check_wasm_bindgen_version(project_path: &Path) -> Result<(), Error> {
+    let required = match find_lockfile(project_path) {
+        Some(path) => {
+            let lockfile = fs::read_to_string(&path)
+                .context(format!("Failed to read {}", path.display()))?;
+            locked_versions(&lockfile, WASM_BINDGEN)?
+        },
+        None => Vec::new()
+    };
+    if required.is_empty() {
+        debug!("No wasm-bindgen version found in Cargo.lock, skipping the version check");
+        return Ok(());
+    }
+
+    let output = match process::Command::new(WASM_BINDGEN).arg("--version").output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(WozError::Build(format!(
+                "wasm-bindgen was not found, install it with '{}'", install_command(&required[0])
+            )).into());
+        },
+        Err(e) => return Err(e).context(WozError::Build(String::from("Failed to run wasm-bindgen")))?
+    };
+    let installed = parse_cli_version(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| WozError::Build(String::from("Unable to get the wasm-bindgen version")))?;
+    debug!("Found wasm-bindgen {}, the project uses {}", installed, required.join(", "));
+
+    if !required.contains(&installed) {
+        return Err(WozError::Build(format!(
+            "The project uses wasm-bindgen {} but version {} is installed, install the matching version with '{}'",
+            required.join(", "), installed, install_command(&required[0])
+        )).into());
+    }
+    Ok(())
+}
+
+/// Checks the app exports the function the index page calls to
+/// start it, or has a start function when the entry is `start`
+fn check_entry(entry: &str, wasm: &[u8], lib: Lib) -> Result<(), Error> {
+    let exports = wasm_binary::exported_functions(wasm)
+        .context(WozError::Build(String::from("Failed to read the wasm exports")))?;
+    // Newer versions of wasm-bindgen export a start function, which
+    // also runs `#[wasm_bindgen(start)]` if there is one, and older
+    // ones use the start section. Neither says whether the app has a
+    // start function of its own so only missing bindings are caught.
+    let found = if entry == START_ENTRY {
+        exports.iter().any(|e| e == "__wbindgen_start") || wasm_binary::has_start_section(wasm)?
+    } else {
+        exports.iter().any(|e| e == entry)
+    };
+    if found {
+        return Ok(());
+    }
+
+    let exported: Vec<&str> = exports.iter()
+        .map(String::as_str)
+        .filter(|e| !e.starts_with("__wbindgen") && !e.starts_with("__wbg"))
+        .collect();
+    let message = match (entry == START_ENTRY, lib) {
+        (true, Lib::Raw) => String::from("The entry is start but the module has no start function"),
+        (true, _) => String::from("The entry is start but the app has no #[wasm_bindgen(start)] function"),
+        (false, Lib::Raw) => format!(
+            "The entry function {} isn't exported by the module, add #[no_mangle] pub extern \"C\" to it or set entry in woz.toml",
+            entry
+        ),
+        (false, _) => format!(
+            "The entry function {} isn't exported by the app, add #[wasm_bindgen] to it or set entry in woz.toml",
+            entry
+        ),
+    };
+    Err(WozError::Config(format!("{}. Exported functions: {}", message, exported.join(", "))).into())
+}
+
+/// Checks the settings that only some libs support
+fn check_lib_config(conf: &Config) -> Result<(), Error> {
+    let lib = conf.lib.unwrap_or_default();
+    if lib.is_self_starting() && conf.js_target == Some(JsTarget::Web) {
+        return Err(WozError::Config(String::from(
+            "js_target \"web\" only works with lib \"wasm-bindgen\" or \"wasm-pack\""
+        )).into());
+    }
+    if lib == Lib::StdWeb && conf.entry.is_some() {
+        return Err(WozError::Config(String::from(
+            "entry isn't used with lib \"std-web\", the app starts from its main function"
+        )).into());
+    }
+    Ok(())
+}
+
+// Resolves app.wasm next to the script rather than the page, which
+// is in another directory when served from the app's public URL
+const SCRIPT_RELATIVE_WASM_URL: &str = "new URL(\"app.wasm\", document.currentScript.src).href";
+
+/// Points the script generated by cargo-web at app.wasm. It fetches
+/// the wasm file by the name cargo gave it, relative to the page.
+fn rename_stdweb_wasm(js: &str, wasm_name: &str) -> String {
+    let mut renamed = js.to_owned();
+    for quote in ["\"", "'"].iter() {
+        renamed = renamed.replace(&format!("{}{}{}", quote, wasm_name, quote),
+                                  SCRIPT_RELATIVE_WASM_URL);
+    }
+    if renamed == js {
+        warn!("Unable to find {} in the cargo-web script, the app may fail to load", wasm_name);
+    }
+    renamed
+}
+
+/// Runs a tool that compiles the project, showing its output. With
+/// `--output json` only its stderr is shown so stdout holds just the
+/// result.
+fn run_compiler(mut command: process::Command, name: &str) -> Result<(), Error> {
+    if output::is_json() {
+        command.stdout(process::Stdio::null());
+    }
+    debug!("Running {:?}", command);
+    let started = Instant::now();
+    let status = match command.status() {
+        Ok(status) => status,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(WozError::Build(format!("{} was not found, please install it", name)).into());
+        },
+        Err(e) => return Err(e).context(WozError::Build(format!("Failed to run {}", name)))?
+    };
+    if !status.success() {
+        return Err(WozError::Build(format!("{} failed, please check output above.", name)).into())
+    }
+    info!("Compiled with {} in {:.2?}", name, started.elapsed());
+    Ok(())
+}
+
+/// Reads the script and wasm file generated for the app
+fn read_output(js_path: &Path, wasm_path: &Path) -> Result<(String, Vec<u8>), Error> {
+    let js = fs::read_to_string(js_path)
+        .context(format!("Failed to read js file {}", js_path.display()))?;
+    let wasm = fs::read(wasm_path)
+        .context(format!("Failed to read wasm file {}", wasm_path.display()))?;
+    Ok((js, wasm))
+}
+
 pub struct WasmComponent<'a> {
+    conf: &'a Config,
+    project_path: &'a Path,
     wasm_path: PathBuf,
-    out_path: &'a PathBuf
+    out_path: &'a PathBuf,
+    templates: &'a Handlebars<'a>
 }
 
 impl<'a> WasmComponent<'a> {
-    pub fn new(wasm_path: PathBuf, out_path: &'a PathBuf) -> Self {
-        Self { wasm_path, out_path }
+    pub fn new(conf: &'a Config, project_path: &'a Path, wasm_path: PathBuf,
+               out_path: &'a PathBuf, templates: &'a Handlebars) -> Self {
+        Self { conf, project_path, wasm_path, out_path, templates }
+    }
+
+    fn release(&self) -> bool {
+        matches!(self.conf.env, Some(Environment::Production))
+    }
+
+    /// Generates bindings for the cargo output with wasm-bindgen,
+    /// returning the script and wasm file
+    fn wasm_bindgen(&self) -> Result<(String, Vec<u8>), Error> {
+        check_wasm_bindgen_version(self.project_path)?;
+
+        let mut command = process::Command::new(WASM_BINDGEN);
+        command
+            .arg(&self.wasm_path)
+            .args(self.conf.js_target.unwrap_or_default().wasm_bindgen_args())
+            .args(["--no-typescript", "--out-name", "app", "--out-dir"])
+            .arg(self.out_path);
+
+        debug!("Running {:?}", command);
+        let started = Instant::now();
+        let output = command.output()
+            .context(WozError::Build(String::from("Failed to run wasm-bindgen")))?;
+        if !output.status.success() {
+            return Err(WozError::Build(format!(
+                "wasm-bindgen failed: {}", String::from_utf8_lossy(&output.stderr).trim()
+            )).into())
+        };
+        info!("Generated bindings with wasm-bindgen in {:.2?}", started.elapsed());
+
+        read_output(&self.out_path.join("app.js"), &self.out_path.join("app_bg.wasm"))
+    }
+
+    /// Builds the project with wasm-pack, which installs its own
+    /// wasm-bindgen, and reads the files it writes to `pkg`
+    fn wasm_pack(&self) -> Result<(String, Vec<u8>), Error> {
+        let target = match self.conf.js_target.unwrap_or_default() {
+            JsTarget::NoModules => "no-modules",
+            JsTarget::Web => "web",
+        };
+        let mut command = process::Command::new("wasm-pack");
+        command
+            .current_dir(self.project_path)
+            .args(["build", "--target", target, "--no-typescript", "--out-name", "app"])
+            .arg(if self.release() { "--release" } else { "--dev" });
+        run_compiler(command, "wasm-pack")?;
+
+        let pkg_path = self.project_path.join("pkg");
+        read_output(&pkg_path.join("app.js"), &pkg_path.join("app_bg.wasm"))
+    }
+
+    /// Builds the project with cargo-web. `wasm_path` points to the
+    /// wasm file and the script is next to it.
+    fn cargo_web(&self) -> Result<(String, Vec<u8>), Error> {
+        let mut command = process::Command::new("cargo");
+        command
+            .current_dir(self.project_path)
+            .args(["web", "build", "--target", "wasm32-unknown-unknown"]);
+        if self.release() {
+            command.arg("--release");
+        }
+        run_compiler(command, "cargo web")?;
+
+        let (js, wasm) = read_output(&self.wasm_path.with_extension("js"), &self.wasm_path)?;
+        let wasm_name = self.wasm_path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok((rename_stdweb_wasm(&js, &wasm_name), wasm))
+    }
+
+    /// Reads the cargo output and generates a script that loads it
+    /// and calls the entry function
+    fn raw(&self) -> Result<(String, Vec<u8>), Error> {
+        let wasm = fs::read(&self.wasm_path)
+            .context(format!("Failed to read wasm file {}", self.wasm_path.display()))?;
+        let entry = self.conf.entry.as_deref().unwrap_or(DEFAULT_ENTRY);
+        let js = self.templates.render("loader.js", &json!({
+            "entry": if entry == START_ENTRY { None } else { Some(entry) }
+        })).context("Failed to render app.js")?;
+        Ok((js, wasm))
     }
 }
 
 impl<'a> AppComponent for WasmComponent<'a> {
     fn files(&self, file_prefix: &str) -> Result<Vec<FileUpload>, Error> {
-        let command = format!(
-            "wasm-bindgen {} --no-typescript --no-modules --out-dir {} --out-name app",
-            self.wasm_path.clone().into_os_string().into_string().unwrap(),
-            self.out_path.clone().into_os_string().into_string().unwrap()
-        );
-
-        let mut bindgen_proc = process::Command::new("sh")
-            .arg("-c")
-            .arg(command)
-            .stdout(process::Stdio::piped())
-            .spawn()
-            .context("Failed to spawn wasm-bindgen")?;
-        let exit_code = bindgen_proc.wait().context("Failed to wait for bindings")?;
-        if !exit_code.success() {
-            return Err(format_err!("wasm-bindgen failed"))
-        };
-
-        let mut js_path = self.out_path.clone();
-        js_path.push("app.js");
+        check_lib_config(self.conf)?;
 
-        let mut wasm_path = self.out_path.clone();
-        wasm_path.push("app_bg.wasm");
+        let lib = self.conf.lib.unwrap_or_default();
+        let (js, wasm) = match lib {
+            Lib::WasmBindgen => self.wasm_bindgen()?,
+            Lib::WasmPack => self.wasm_pack()?,
+            Lib::StdWeb => self.cargo_web()?,
+            Lib::Raw => self.raw()?,
+        };
+        // cargo-web apps start from main instead of an entry function
+        if lib != Lib::StdWeb {
+            check_entry(self.conf.entry.as_deref().unwrap_or(DEFAULT_ENTRY), &wasm, lib)?;
+        }
 
         let uploads = vec![
             FileUpload::new(
                 format!("{}/app/app.js", &file_prefix),
                 String::from("application/javascript"),
-                fs::read_to_string(js_path).context("Failed to read js file")?.into_bytes()
+                js.into_bytes()
             ),
             FileUpload::new(
                 format!("{}/app/app.wasm", &file_prefix),
                 String::from("application/wasm"),
-                {
-                    let mut f = File::open(wasm_path).context("Failed to read wasm file")?;
-                    let mut buffer = Vec::new();
-                    f.read_to_end(&mut buffer).context("Failed to read to bytes")?;
-                    buffer
-                }
+                wasm_opt::optimize(wasm, self.conf.optimize.unwrap_or_default(), self.out_path)?
             ),
         ];
 
         Ok(uploads)
     }
+
+    fn uses_build_output(&self) -> bool {
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod wasm_tests {
+    use super::*;
+
+    #[test]
+    fn locked_versions_works() {
+        let lockfile = "\
+version = 3
+
+[[package]]
+name = \"wasm-bindgen\"
+version = \"0.2.100\"
+dependencies = [\"wasm-bindgen-macro\"]
+
+[[package]]
+name = \"wasm-bindgen-macro\"
+version = \"0.2.100\"
+";
+        assert_eq!(locked_versions(lockfile, "wasm-bindgen").unwrap(), vec!["0.2.100"]);
+        assert!(locked_versions(lockfile, "stdweb").unwrap().is_empty());
+        assert!(locked_versions("[[package]", "wasm-bindgen").is_err());
+    }
+
+    #[test]
+    fn check_entry_works() {
+        use crate::wasm_binary::wasm_binary_tests::{module, section};
+
+        let exports = section(7, b"\x02\x06render\x00\x01\x0f__wbindgen_free\x00\x02");
+        let wasm = module(&[exports]);
+        assert!(check_entry("render", &wasm, Lib::WasmBindgen).is_ok());
+        let error = check_entry("main", &wasm, Lib::WasmBindgen).unwrap_err();
+        assert!(error.to_string().contains("Exported functions: render"));
+        assert!(check_entry(START_ENTRY, &wasm, Lib::WasmBindgen).is_err());
+
+        let exports = section(7, b"\x01\x10__wbindgen_start\x00\x01");
+        assert!(check_entry(START_ENTRY, &module(&[exports]), Lib::WasmBindgen).is_ok());
+
+        let error = check_entry("main", &wasm, Lib::Raw).unwrap_err();
+        assert!(error.to_string().contains("#[no_mangle]"));
+        assert!(check_entry(START_ENTRY, &module(&[section(8, &[0])]), Lib::Raw).is_ok());
+    }
+
+    #[test]
+    fn check_lib_config_works() {
+        let conf = Config { lib: Some(Lib::Raw), js_target: Some(JsTarget::Web), ..Default::default() };
+        assert!(check_lib_config(&conf).is_err());
+
+        let conf = Config { lib: Some(Lib::WasmPack), js_target: Some(JsTarget::Web), ..Default::default() };
+        assert!(check_lib_config(&conf).is_ok());
+
+        let conf = Config { lib: Some(Lib::StdWeb), entry: Some(String::from("main")), ..Default::default() };
+        assert!(check_lib_config(&conf).is_err());
+    }
+
+    #[test]
+    fn rename_stdweb_wasm_works() {
+        let js = "return fetch( \"my-app.wasm\", {credentials: \"same-origin\"} )";
+        assert_eq!(rename_stdweb_wasm(js, "my-app.wasm"),
+                   "return fetch( new URL(\"app.wasm\", document.currentScript.src).href, {credentials: \"same-origin\"} )");
+        assert_eq!(rename_stdweb_wasm("fetch('other.wasm')", "my-app.wasm"), "fetch('other.wasm')");
+    }
+
+    #[test]
+    fn parse_cli_version_works() {
+        assert_eq!(parse_cli_version("wasm-bindgen 0.2.100\n"), Some(String::from("0.2.100")));
+        assert_eq!(parse_cli_version(""), None);
+    }
 }
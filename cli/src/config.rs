@@ -1,5 +1,5 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Deserializer};
 use failure::Error;
 use regex::Regex;
@@ -14,6 +14,18 @@ pub const S3_BUCKET_NAME: &str = env!("WOZ_S3_BUCKET_NAME");
 
 pub const MAX_APP_SIZE_MB: usize = 20;
 
+/// Exported function called once the app is loaded
+pub const DEFAULT_ENTRY: &str = "render";
+/// Entry for apps using `#[wasm_bindgen(start)]`, which runs when the
+/// module is loaded so nothing needs to be called
+pub const START_ENTRY: &str = "start";
+/// Id of the element the app is rendered into
+pub const DEFAULT_MOUNT_ID: &str = "app";
+
+// Target triple this binary was compiled for, set by build.rs
+pub const TARGET: &str = env!("WOZ_TARGET");
+pub const DEFAULT_UPDATE_INDEX_URL: &str = "https://woz.sh/bin/releases.json";
+
 pub const ENCRYPTION_PASSWORD: &str = env!("WOZ_ENCRYPTION_PASSWORD");
 pub const ENCRYPTION_SALT: &str = env!("WOZ_ENCRYPTION_SALT");
 
@@ -86,11 +98,62 @@ lazy_static!{
     };
 }
 
-#[derive(Debug, Serialize)]
+/// Sizes in pixels of the icons generated from `icon`
+pub const ICON_SIZES: [(&str, u32); 10] = [
+    ("48x48", 48),
+    ("72x72", 72),
+    ("96x96", 96),
+    ("144x144", 144),
+    ("152x152", 152),
+    ("167x167", 167),
+    ("168x168", 168),
+    ("180x180", 180),
+    ("192x192", 192),
+    ("512x512", 512),
+];
+
+/// Width and height in pixels of the splashscreens generated from
+/// `icon` for each device
+pub const SPLASHSCREEN_SIZES: [(&str, u32, u32); 10] = [
+    ("iphone5", 640, 1136),
+    ("iphone6", 750, 1334),
+    ("iphoneplus", 1242, 2208),
+    ("iphonex", 1125, 2436),
+    ("iphonexr", 828, 1792),
+    ("iphonexsmax", 1242, 2688),
+    ("ipad", 1536, 2048),
+    ("ipadpro1", 1668, 2224),
+    ("ipadpro3", 1668, 2388),
+    ("ipadpro2", 2048, 2732),
+];
+
+/// The library the app is built with, which decides how it's
+/// compiled and loaded
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Default)]
 pub enum Lib {
+    /// Compiled with cargo and bound with the wasm-bindgen cli
+    #[default]
     WasmBindgen,
+    /// Compiled and bound with `wasm-pack build`
+    WasmPack,
+    /// Compiled with `cargo web build`
     StdWeb,
-    Unknown(String)
+    /// Compiled with cargo and loaded without any bindings
+    Raw,
+}
+
+impl Lib {
+    /// Returns true if the project is compiled with `cargo build`.
+    /// wasm-pack and cargo-web compile the project themselves.
+    pub fn uses_cargo_build(&self) -> bool {
+        matches!(self, Lib::WasmBindgen | Lib::Raw)
+    }
+
+    /// Returns true if the app runs itself once its script is
+    /// loaded rather than being started by the index page
+    pub fn is_self_starting(&self) -> bool {
+        matches!(self, Lib::StdWeb | Lib::Raw)
+    }
 }
 
 impl<'de> Deserialize<'de> for Lib {
@@ -98,11 +161,15 @@ impl<'de> Deserialize<'de> for Lib {
         where D: Deserializer<'de>
     {
         let s = String::deserialize(deserializer)?;
-        Ok(match s.as_str() {
-            "wasm-bindgen" => Lib::WasmBindgen,
-            "std-web" => Lib::StdWeb,
-            _ => Lib::Unknown(s),
-        })
+        match s.as_str() {
+            "wasm-bindgen" => Ok(Lib::WasmBindgen),
+            "wasm-pack" => Ok(Lib::WasmPack),
+            "std-web" | "cargo-web" => Ok(Lib::StdWeb),
+            "raw" => Ok(Lib::Raw),
+            _ => Err(serde::de::Error::custom(format!(
+                "unknown lib {}, use one of wasm-bindgen, wasm-pack, std-web or raw", s
+            )))
+        }
     }
 }
 
@@ -126,6 +193,62 @@ impl<'de> Deserialize<'de> for Environment {
     }
 }
 
+/// How much the wasm file is optimized after running wasm-bindgen
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OptimizeLevel {
+    /// Ship the wasm file as is
+    None,
+    /// Remove custom sections such as names and debug info
+    #[default]
+    Strip,
+    /// Strip and run `wasm-opt` with the matching `-O` flag
+    #[serde(rename = "1")]
+    O1,
+    #[serde(rename = "2")]
+    O2,
+    #[serde(rename = "3")]
+    O3,
+    #[serde(rename = "4")]
+    O4,
+    S,
+    Z,
+}
+
+impl OptimizeLevel {
+    pub fn wasm_opt_flag(&self) -> Option<&'static str> {
+        match self {
+            OptimizeLevel::None | OptimizeLevel::Strip => None,
+            OptimizeLevel::O1 => Some("-O1"),
+            OptimizeLevel::O2 => Some("-O2"),
+            OptimizeLevel::O3 => Some("-O3"),
+            OptimizeLevel::O4 => Some("-O4"),
+            OptimizeLevel::S => Some("-Os"),
+            OptimizeLevel::Z => Some("-Oz"),
+        }
+    }
+}
+
+/// The kind of JavaScript wasm-bindgen generates for loading the app
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JsTarget {
+    /// A script that defines a global `wasm_bindgen` function
+    #[default]
+    NoModules,
+    /// An ES module loaded with `<script type="module">`
+    Web,
+}
+
+impl JsTarget {
+    pub fn wasm_bindgen_args(&self) -> &'static [&'static str] {
+        match self {
+            JsTarget::NoModules => &["--no-modules"],
+            JsTarget::Web => &["--target", "web"],
+        }
+    }
+}
+
 lazy_static! {
     static ref PROJECT_ID_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9-_]+$").unwrap();
 }
@@ -213,6 +336,88 @@ impl SplashScreens {
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: Option<String>,
+    // Set this to use an S3 compatible service other than AWS
+    pub endpoint: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    // Optional path in the bucket to deploy apps under
+    pub prefix: Option<String>,
+    // Base URL the bucket is publicly served from
+    pub public_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DirectoryConfig {
+    pub path: PathBuf,
+    // Base URL the directory is publicly served from
+    pub public_url: Option<String>,
+}
+
+/// How the service worker handles requests matching a route
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheStrategy {
+    CacheFirst,
+    NetworkFirst,
+    StaleWhileRevalidate,
+    NetworkOnly,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RouteConfig {
+    // JavaScript regular expression matched against the full request
+    // URL, checked by the browser
+    pub pattern: String,
+    pub strategy: CacheStrategy,
+    pub max_age_seconds: Option<u64>,
+    pub max_entries: Option<usize>,
+}
+
+/// Settings for the `[service_worker]` section of the config. Routes
+/// are checked in order and requests that don't match any route use
+/// `default_strategy`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ServiceWorkerConfig {
+    pub routes: Vec<RouteConfig>,
+    pub default_strategy: Option<CacheStrategy>,
+    // Page shown when navigating while offline to a page that isn't
+    // cached
+    pub offline_page: Option<PathBuf>,
+    // Defaults for routes that don't set their own limits
+    pub max_age_seconds: Option<u64>,
+    pub max_entries: Option<usize>,
+}
+
+/// Size limits for the `[budget]` section of the config. Sizes are
+/// written like `500kB` or `2MB`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BudgetConfig {
+    /// Total size of every file in the app
+    pub total: Option<String>,
+    /// Total size of the WebAssembly files
+    pub wasm: Option<String>,
+    /// Limit for each file matching a glob such as `app/img/**`
+    pub files: BTreeMap<String, String>,
+    /// Checks gzip compressed sizes instead of raw sizes
+    pub gzip: bool,
+}
+
+/// Where `woz deploy` uploads the app to, selected by the `target`
+/// key in the `[deploy]` section of the config.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "target", rename_all = "lowercase")]
+pub enum DeployConfig {
+    Woz,
+    S3(S3Config),
+    Directory(DirectoryConfig),
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(default)]
 pub struct Config {
@@ -225,9 +430,23 @@ pub struct Config {
     pub description: Option<String>,
     pub env: Option<Environment>,
     pub wasm_path: PathBuf,
+    /// Defaults to stripping custom sections from the wasm file
+    pub optimize: Option<OptimizeLevel>,
+    pub js_target: Option<JsTarget>,
+    /// Exported function that starts the app or `start`
+    pub entry: Option<String>,
+    pub mount_id: Option<String>,
+    /// A high resolution PNG or SVG that icons and splashscreens are
+    /// generated from when they aren't listed individually
+    pub icon: Option<PathBuf>,
     pub icons: Option<Icons>,
     pub splashscreens: Option<SplashScreens>,
-    pub bg_color: Option<String>
+    pub bg_color: Option<String>,
+    /// Appends the git commit of the project to the build version
+    pub version_git_commit: Option<bool>,
+    pub service_worker: Option<ServiceWorkerConfig>,
+    pub budget: Option<BudgetConfig>,
+    pub deploy: Option<DeployConfig>
 }
 
 impl Default for Config {
@@ -242,9 +461,18 @@ impl Default for Config {
             description: Some(String::from("App built with woz.sh")),
             env: Some(Environment::Development),
             wasm_path: PathBuf::new(),
+            optimize: None,
+            js_target: None,
+            entry: None,
+            mount_id: None,
+            icon: None,
             icons: None,
             splashscreens: None,
-            bg_color: Some(String::from("#ffffff"))
+            bg_color: Some(String::from("#ffffff")),
+            version_git_commit: None,
+            service_worker: None,
+            budget: None,
+            deploy: None
         }
     }
 }
@@ -260,6 +488,32 @@ pub fn default_home_path() -> Result<PathBuf, Error> {
     Ok(buf)
 }
 
+/// Environment variable that selects the profile when `--profile`
+/// isn't given
+pub const PROFILE_VAR: &str = "WOZ_PROFILE";
+
+/// Returns the directory where credentials for the profile are
+/// cached. The default profile uses the home directory itself so
+/// existing logins keep working.
+pub fn profile_path(home_path: &Path, profile: Option<&str>) -> Result<PathBuf, Error> {
+    let mut path = home_path.to_path_buf();
+    match profile {
+        None | Some("default") => (),
+        Some(name) => {
+            let valid = !name.is_empty() && name.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Err(format_err!(
+                    "Invalid profile name {}, use letters, numbers, - and _", name
+                ));
+            }
+            path.push("profiles");
+            path.push(name);
+        }
+    };
+    Ok(path)
+}
+
 #[cfg(all(target_os="macos"))]
 #[test]
 fn default_home_path_test() {
@@ -285,6 +539,131 @@ wasm_path=\"target/wasm32-unknown-unknown/release/seed_app.wasm\"\
     assert!(conf.description.is_some());
 }
 
+#[test]
+fn deploy_config_test() {
+    use super::*;
+    let conf_str = "\
+name=\"Woz Example App\"
+project_id=\"seed\"
+wasm_path=\"target/wasm32-unknown-unknown/release/seed_app.wasm\"
+
+[deploy]
+target=\"s3\"
+endpoint=\"http://localhost:9000\"
+bucket=\"apps\"
+";
+    let conf: Config = toml::from_str(conf_str).unwrap();
+    match conf.deploy {
+        Some(DeployConfig::S3(s3)) => {
+            assert_eq!(s3.bucket, "apps");
+            assert_eq!(s3.endpoint.unwrap(), "http://localhost:9000");
+        },
+        _ => panic!("Expected an S3 deploy config")
+    }
+
+    let conf_str = "\
+project_id=\"seed\"
+
+[deploy]
+target=\"directory\"
+path=\"/var/www\"
+";
+    let conf: Config = toml::from_str(conf_str).unwrap();
+    assert!(matches!(conf.deploy, Some(DeployConfig::Directory(_))));
+}
+
+#[test]
+fn service_worker_config_test() {
+    use super::*;
+    let conf_str = "\
+project_id=\"seed\"
+
+[service_worker]
+offline_page=\"offline.html\"
+max_entries=50
+
+[[service_worker.routes]]
+pattern=\"^https://api\\\\.example\\\\.com/\"
+strategy=\"network-first\"
+max_age_seconds=300
+";
+    let conf: Config = toml::from_str(conf_str).unwrap();
+    let sw = conf.service_worker.unwrap();
+    assert_eq!(sw.routes.len(), 1);
+    assert_eq!(sw.routes[0].pattern, "^https://api\\.example\\.com/");
+    assert_eq!(sw.routes[0].strategy, CacheStrategy::NetworkFirst);
+    assert_eq!(sw.routes[0].max_age_seconds, Some(300));
+    assert_eq!(sw.max_entries, Some(50));
+    assert!(sw.default_strategy.is_none());
+
+    let conf_str = "\
+[[service_worker.routes]]
+pattern=\".*\"
+strategy=\"sometimes\"
+";
+    assert!(toml::from_str::<Config>(conf_str).is_err());
+}
+
+#[test]
+fn optimize_config_test() {
+    let conf: Config = toml::from_str("project_id=\"seed\"\noptimize=\"z\"").unwrap();
+    assert_eq!(conf.optimize, Some(OptimizeLevel::Z));
+    assert_eq!(conf.optimize.unwrap().wasm_opt_flag(), Some("-Oz"));
+
+    let conf: Config = toml::from_str("project_id=\"seed\"\noptimize=\"3\"").unwrap();
+    assert_eq!(conf.optimize, Some(OptimizeLevel::O3));
+
+    assert!(toml::from_str::<Config>("project_id=\"seed\"\noptimize=\"fast\"").is_err());
+}
+
+#[test]
+fn js_target_config_test() {
+    let conf: Config = toml::from_str("project_id=\"seed\"\njs_target=\"web\"").unwrap();
+    assert_eq!(conf.js_target, Some(JsTarget::Web));
+
+    let conf: Config = toml::from_str("project_id=\"seed\"\njs_target=\"no-modules\"").unwrap();
+    assert_eq!(conf.js_target, Some(JsTarget::NoModules));
+
+    assert!(toml::from_str::<Config>("project_id=\"seed\"\njs_target=\"node\"").is_err());
+}
+
+#[test]
+fn lib_config_test() {
+    let conf: Config = toml::from_str("project_id=\"seed\"\nlib=\"wasm-pack\"").unwrap();
+    assert_eq!(conf.lib, Some(Lib::WasmPack));
+    assert!(!conf.lib.unwrap().uses_cargo_build());
+
+    let conf: Config = toml::from_str("project_id=\"seed\"\nlib=\"cargo-web\"").unwrap();
+    assert_eq!(conf.lib, Some(Lib::StdWeb));
+
+    let conf: Config = toml::from_str("project_id=\"seed\"\nlib=\"raw\"").unwrap();
+    assert!(conf.lib.unwrap().uses_cargo_build());
+    assert!(conf.lib.unwrap().is_self_starting());
+
+    let error = toml::from_str::<Config>("project_id=\"seed\"\nlib=\"yew\"").unwrap_err();
+    assert!(error.to_string().contains("unknown lib yew"));
+}
+
+#[test]
+fn budget_config_test() {
+    let conf_str = "\
+project_id=\"seed\"
+
+[budget]
+total=\"2MB\"
+gzip=true
+
+[budget.files]
+\"app/img/**\"=\"100kB\"
+";
+    let conf: Config = toml::from_str(conf_str).unwrap();
+    let budget = conf.budget.unwrap();
+    assert_eq!(budget.total.unwrap(), "2MB");
+    assert_eq!(budget.wasm, None);
+    assert_eq!(budget.files.get("app/img/**").unwrap(), "100kB");
+    assert!(budget.gzip);
+}
+
 #[test]
 fn project_id_test() {
     use super::*;
@@ -298,3 +677,14 @@ fn project_id_test() {
     assert_eq!(ProjectId::is_valid(&invalid1), false);
     assert_eq!(ProjectId::is_valid(&invalid2), false);
 }
+
+#[test]
+fn profile_path_test() {
+    let home = PathBuf::from("/home/user/.woz");
+    assert_eq!(profile_path(&home, None).unwrap(), home);
+    assert_eq!(profile_path(&home, Some("default")).unwrap(), home);
+    assert_eq!(profile_path(&home, Some("team_1")).unwrap(),
+               PathBuf::from("/home/user/.woz/profiles/team_1"));
+    assert!(profile_path(&home, Some("../team")).is_err());
+    assert!(profile_path(&home, Some("")).is_err());
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use failure::Error;
+use failure::ResultExt;
+
+use crate::config::DirectoryConfig;
+use crate::file_upload::FileUpload;
+use super::DeployTarget;
+
+
+/// Deploys to a directory on the local filesystem, for example one
+/// that is served by a web server. Files are written uncompressed.
+pub struct DirectoryTarget {
+    path: PathBuf,
+    public_url: Option<String>,
+}
+
+impl DirectoryTarget {
+    pub fn new(path: PathBuf, public_url: Option<String>) -> Self {
+        Self { path, public_url }
+    }
+
+    pub fn from_config(conf: &DirectoryConfig) -> Self {
+        Self::new(conf.path.clone(), conf.public_url.clone())
+    }
+}
+
+#[async_trait]
+impl DeployTarget for DirectoryTarget {
+    fn key_prefix(&self, project_id: &str) -> String {
+        project_id.to_owned()
+    }
+
+    fn url(&self, project_id: &str) -> String {
+        match &self.public_url {
+            Some(url) => format!("{}/{}/index.html", url.trim_end_matches('/'), project_id),
+            None => {
+                let path = fs::canonicalize(&self.path).unwrap_or_else(|_| self.path.clone());
+                format!("file://{}/{}/index.html", path.display(), project_id)
+            }
+        }
+    }
+
+    async fn put(&self, file: &FileUpload) -> Result<(), Error> {
+        let mut path = self.path.clone();
+        path.push(&file.filename);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("Failed to make directory")?;
+        }
+        fs::write(&path, &file.bytes)
+            .context(format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let mut path = self.path.clone();
+        path.push(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = fs::read(&path)
+            .context(format!("Failed to read {}", path.display()))?;
+        Ok(Some(bytes))
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), Error> {
+        let from_path = self.path.join(from);
+        let to_path = self.path.join(to);
+        if let Some(dir) = to_path.parent() {
+            fs::create_dir_all(dir).context("Failed to make directory")?;
+        }
+        fs::copy(&from_path, &to_path)
+            .context(format!("Failed to copy {} to {}", from_path.display(), to_path.display()))?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod directory_tests {
+    use super::*;
+    use std::env;
+
+    #[tokio::test]
+    async fn put_works() {
+        let mut dir = env::temp_dir();
+        dir.push("woz-directory-target-test");
+        let target = DirectoryTarget::new(dir.clone(), Some(String::from("https://example.com/")));
+        let file = FileUpload::new(
+            format!("{}/app/index.html", target.key_prefix("myapp")),
+            String::from("text/html"),
+            b"<html></html>".to_vec()
+        );
+        target.put(&file).await.unwrap();
+
+        assert_eq!(target.get(&file.filename).await.unwrap().unwrap(), b"<html></html>");
+        assert!(target.get("myapp/missing.js").await.unwrap().is_none());
+
+        target.copy(&file.filename, "myapp/copy/index.html").await.unwrap();
+        assert_eq!(target.get("myapp/copy/index.html").await.unwrap().unwrap(), b"<html></html>");
+
+        dir.push("myapp/app/index.html");
+        assert_eq!(fs::read(dir).unwrap(), b"<html></html>");
+        assert_eq!(target.url("myapp"), "https://example.com/myapp/index.html");
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use failure::Error;
+use failure::ResultExt;
+
+use crate::file_upload::FileUpload;
+use super::DeployTarget;
+
+
+/// Name of the manifest file stored alongside a deployed app
+pub const MANIFEST_FILE_NAME: &str = ".woz-manifest.json";
+
+/// Returns the file name with the key prefix removed
+pub fn relative_name<'a>(filename: &'a str, key_prefix: &str) -> &'a str {
+    filename.strip_prefix(key_prefix)
+        .map(|name| name.trim_start_matches('/'))
+        .unwrap_or(filename)
+}
+
+/// Records the content hash of every file in a release so the next
+/// deploy only needs to upload the files that changed.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeployManifest {
+    /// File names relative to the release mapped to the SHA-256 hash
+    /// of their contents
+    pub files: HashMap<String, String>,
+}
+
+impl DeployManifest {
+    /// Creates a manifest of the files, which are all expected to
+    /// start with `key_prefix`
+    pub fn from_files(files: &[FileUpload], key_prefix: &str) -> Self {
+        let files = files.iter()
+            .map(|f| (relative_name(&f.filename, key_prefix).to_owned(), f.hash()))
+            .collect();
+        Self { files }
+    }
+
+    /// Returns the key of the manifest for the app at `key_prefix`
+    pub fn key(key_prefix: &str) -> String {
+        format!("{}/{}", key_prefix, MANIFEST_FILE_NAME)
+    }
+
+    /// Fetches the manifest stored at `key_prefix`. Returns an empty
+    /// manifest if there isn't one.
+    pub async fn fetch(target: &dyn DeployTarget, key_prefix: &str) -> Result<Self, Error> {
+        let manifest = match target.get(&Self::key(key_prefix)).await? {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .context("Failed to parse deploy manifest")?,
+            None => Self::default()
+        };
+        Ok(manifest)
+    }
+
+    /// Returns true if the file is in `previous` with the same hash
+    pub fn unchanged(&self, previous: &Self, filename: &str) -> bool {
+        match (self.files.get(filename), previous.files.get(filename)) {
+            (Some(hash), Some(prev_hash)) => hash == prev_hash,
+            _ => false
+        }
+    }
+
+    /// Returns the manifest as a file that can be uploaded
+    pub fn to_file(&self, key_prefix: &str) -> Result<FileUpload, Error> {
+        let bytes = serde_json::to_vec(self).context("Failed to serialize deploy manifest")?;
+        Ok(FileUpload::new(Self::key(key_prefix), String::from("application/json"), bytes))
+    }
+}
+
+
+#[cfg(test)]
+mod manifest_tests {
+    use super::*;
+
+    fn file(filename: &str, contents: &[u8]) -> FileUpload {
+        FileUpload::new(String::from(filename), String::from("text/plain"), contents.to_vec())
+    }
+
+    #[test]
+    fn unchanged_works() {
+        let previous = DeployManifest::from_files(&[
+            file("v1/app/a.txt", b"a"),
+            file("v1/app/b.txt", b"b"),
+        ], "v1");
+        let current = DeployManifest::from_files(&[
+            file("v2/app/a.txt", b"a"),
+            file("v2/app/b.txt", b"changed"),
+            file("v2/app/c.txt", b"c"),
+        ], "v2");
+        assert!(current.unchanged(&previous, "app/a.txt"));
+        assert!(!current.unchanged(&previous, "app/b.txt"));
+        assert!(!current.unchanged(&previous, "app/c.txt"));
+    }
+
+    #[test]
+    fn to_file_works() {
+        let manifest = DeployManifest::from_files(&[file("myapp/app/a.txt", b"a")], "myapp");
+        assert!(manifest.files.contains_key("app/a.txt"));
+        let upload = manifest.to_file("myapp").unwrap();
+        assert_eq!(upload.filename, "myapp/.woz-manifest.json");
+        let parsed: DeployManifest = serde_json::from_slice(&upload.bytes).unwrap();
+        assert_eq!(parsed, manifest);
+    }
+}
@@ -0,0 +1,60 @@
+use async_trait::async_trait;
+use failure::Error;
+
+use crate::cache::FileCache;
+use crate::upload_client::AuthOptions;
+use crate::config::DeployConfig;
+use crate::file_upload::FileUpload;
+
+pub mod woz;
+pub mod s3;
+pub mod directory;
+pub mod manifest;
+pub mod release;
+
+use self::woz::WozTarget;
+use self::s3::S3Target;
+use self::directory::DirectoryTarget;
+
+
+/// Implement this trait to add a place apps can be deployed to. See
+/// examples in this directory.
+#[async_trait]
+pub trait DeployTarget: Send + Sync {
+    /// Returns the prefix to add to every file name in the app
+    /// bundle. Files are passed to `put` with this prefix.
+    fn key_prefix(&self, project_id: &str) -> String;
+
+    /// Returns the URL where the deployed app can be opened
+    fn url(&self, project_id: &str) -> String;
+
+    /// Returns the maximum size in megabytes allowed for an app, if
+    /// there is one
+    fn max_size_mb(&self) -> Option<usize> {
+        None
+    }
+
+    /// Stores the file so that it is publicly available
+    async fn put(&self, file: &FileUpload) -> Result<(), Error>;
+
+    /// Returns the contents of a previously stored file or None if it
+    /// doesn't exist
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Copies a previously stored file to a new key without
+    /// uploading it again
+    async fn copy(&self, from: &str, to: &str) -> Result<(), Error>;
+}
+
+/// Returns the deploy target selected in the woz config. Defaults to
+/// the woz.sh service which requires the user to be logged in.
+pub async fn from_config(conf: &Option<DeployConfig>,
+                         cache: &FileCache,
+                         auth: &AuthOptions) -> Result<Box<dyn DeployTarget>, Error> {
+    let target: Box<dyn DeployTarget> = match conf {
+        None | Some(DeployConfig::Woz) => Box::new(WozTarget::new(cache, auth).await?),
+        Some(DeployConfig::S3(s3_conf)) => Box::new(S3Target::from_config(s3_conf)?),
+        Some(DeployConfig::Directory(dir_conf)) => Box::new(DirectoryTarget::from_config(dir_conf)),
+    };
+    Ok(target)
+}
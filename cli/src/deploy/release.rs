@@ -0,0 +1,284 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+use failure::ResultExt;
+
+use crate::file_upload::FileUpload;
+use super::DeployTarget;
+
+
+/// Name of the file that lists every release of an app
+pub const RELEASES_FILE_NAME: &str = "releases.json";
+
+/// Directory in a release with the files that are served from the
+/// app's public URLs while it's live. Their links point back into the
+/// release.
+pub const STABLE_DIR: &str = "stable";
+
+// Files at the app's public URLs paired with the file in the current
+// release they are copied from. The app shell, its manifest and
+// service worker stay at the same URL across releases so an
+// installed app picks up new deploys. The pages are copied last so
+// they're only switched once the files they use are.
+const STABLE_FILES: [(&str, &str); 4] = [
+    ("app/sw.js", "stable/app/sw.js"),
+    ("app/manifest.json", "stable/app/manifest.json"),
+    ("app/index.html", "stable/app/index.html"),
+    ("index.html", "index.html"),
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Release {
+    pub version: String,
+    /// Seconds since the unix epoch
+    pub deployed_at: u64,
+}
+
+/// The releases of an app, oldest first. Each release is stored
+/// under its own immutable prefix and the app's public URLs redirect
+/// to the current one.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Releases {
+    pub current: Option<String>,
+    pub releases: Vec<Release>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Releases {
+    /// Returns the path of the release relative to the app's key
+    /// prefix
+    pub fn path(version: &str) -> String {
+        format!("releases/{}", version)
+    }
+
+    /// Returns the prefix files of the release are stored under
+    pub fn prefix(key_prefix: &str, version: &str) -> String {
+        format!("{}/{}", key_prefix, Self::path(version))
+    }
+
+    fn key(key_prefix: &str) -> String {
+        format!("{}/{}", key_prefix, RELEASES_FILE_NAME)
+    }
+
+    /// Fetches the releases of the app at `key_prefix`. Returns no
+    /// releases if the app hasn't been deployed before.
+    pub async fn fetch(target: &dyn DeployTarget, key_prefix: &str) -> Result<Self, Error> {
+        let releases = match target.get(&Self::key(key_prefix)).await? {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .context("Failed to parse releases")?,
+            None => Self::default()
+        };
+        Ok(releases)
+    }
+
+    pub fn get(&self, version: &str) -> Option<&Release> {
+        self.releases.iter().find(|r| r.version == version)
+    }
+
+    /// Returns the release deployed before the current one
+    pub fn previous(&self) -> Option<&Release> {
+        let current = self.current.as_ref()?;
+        let i = self.releases.iter().position(|r| &r.version == current)?;
+        if i == 0 {
+            None
+        } else {
+            self.releases.get(i - 1)
+        }
+    }
+
+    /// Records a newly uploaded release
+    pub fn add(&mut self, version: &str) {
+        if self.get(version).is_none() {
+            self.releases.push(Release {
+                version: version.to_owned(),
+                deployed_at: now()
+            });
+        }
+    }
+
+    /// Makes `version` the live release. The files at the app's
+    /// public URLs are copied from the release first and
+    /// `releases.json`, the pointer to the live release, is only
+    /// written once they all are. If either fails the previous
+    /// release's files are copied back so it stays live.
+    pub async fn activate(&mut self, target: &dyn DeployTarget,
+                          key_prefix: &str, version: &str) -> Result<(), Error> {
+        if self.get(version).is_none() {
+            return Err(format_err!("Release {} was not found", version));
+        }
+
+        let previous = self.current.replace(version.to_owned());
+        if let Err(e) = self.switch(target, key_prefix, version).await {
+            self.current = previous;
+            if let Some(previous) = &self.current {
+                if let Err(restore_error) = Self::copy_stable_files(target, key_prefix, previous).await {
+                    warn!("Failed to restore release {}: {}", previous, restore_error);
+                }
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn switch(&self, target: &dyn DeployTarget, key_prefix: &str,
+                    version: &str) -> Result<(), Error> {
+        Self::copy_stable_files(target, key_prefix, version).await?;
+        let bytes = serde_json::to_vec_pretty(self).context("Failed to serialize releases")?;
+        let file = FileUpload::new(Self::key(key_prefix), String::from("application/json"), bytes);
+        target.put(&file).await.context("Failed to update releases")?;
+        Ok(())
+    }
+
+    async fn copy_stable_files(target: &dyn DeployTarget, key_prefix: &str,
+                               version: &str) -> Result<(), Error> {
+        let release_prefix = Self::prefix(key_prefix, version);
+        for (public, source) in STABLE_FILES.iter() {
+            let to = format!("{}/{}", key_prefix, public);
+            target.copy(&format!("{}/{}", release_prefix, source), &to).await
+                .context(format!("Failed to update {}", to))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns how long ago the release was deployed, e.g. `3 hours ago`
+pub fn age(release: &Release) -> String {
+    let secs = now().saturating_sub(release.deployed_at);
+    let (n, unit) = match secs {
+        0..=59 => (secs, "second"),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86_399 => (secs / 3600, "hour"),
+        _ => (secs / 86_400, "day")
+    };
+    format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" })
+}
+
+
+#[cfg(test)]
+mod release_tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use async_trait::async_trait;
+    use crate::deploy::directory::DirectoryTarget;
+
+    // Fails the first copy to `fail_to`
+    struct FailingTarget {
+        target: DirectoryTarget,
+        fail_to: &'static str,
+        failed: AtomicBool,
+    }
+
+    #[async_trait]
+    impl DeployTarget for FailingTarget {
+        fn key_prefix(&self, project_id: &str) -> String {
+            self.target.key_prefix(project_id)
+        }
+
+        fn url(&self, project_id: &str) -> String {
+            self.target.url(project_id)
+        }
+
+        async fn put(&self, file: &FileUpload) -> Result<(), Error> {
+            self.target.put(file).await
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+            self.target.get(key).await
+        }
+
+        async fn copy(&self, from: &str, to: &str) -> Result<(), Error> {
+            if to == self.fail_to && !self.failed.swap(true, Ordering::SeqCst) {
+                return Err(format_err!("Copy failed"));
+            }
+            self.target.copy(from, to).await
+        }
+    }
+
+    fn write_release(dir: &Path, version: &str) {
+        let release = dir.join("myapp/releases").join(version);
+        for (_, source) in STABLE_FILES.iter() {
+            fs::create_dir_all(release.join(source).parent().unwrap()).unwrap();
+            fs::write(release.join(source), format!("{} {}", version, source)).unwrap();
+        }
+    }
+
+    #[test]
+    fn previous_works() {
+        let mut releases = Releases::default();
+        releases.add("v1");
+        releases.add("v2");
+        assert!(releases.previous().is_none());
+
+        releases.current = Some(String::from("v2"));
+        assert_eq!(releases.previous().unwrap().version, "v1");
+
+        releases.current = Some(String::from("v1"));
+        assert!(releases.previous().is_none());
+    }
+
+    #[tokio::test]
+    async fn activate_works() {
+        let mut dir = env::temp_dir();
+        dir.push("woz-release-test");
+        fs::remove_dir_all(&dir).ok();
+        let target = DirectoryTarget::new(dir.clone(), None);
+
+        let mut releases = Releases::fetch(&target, "myapp").await.unwrap();
+        assert_eq!(releases, Releases::default());
+        assert!(releases.activate(&target, "myapp", "v1").await.is_err());
+
+        write_release(&dir, "v1");
+        releases.add("v1");
+        releases.activate(&target, "myapp", "v1").await.unwrap();
+
+        let fetched = Releases::fetch(&target, "myapp").await.unwrap();
+        assert_eq!(fetched.current, Some(String::from("v1")));
+        let shell = fs::read_to_string(dir.join("myapp/app/index.html")).unwrap();
+        assert_eq!(shell, "v1 stable/app/index.html");
+        let sw = fs::read_to_string(dir.join("myapp/app/sw.js")).unwrap();
+        assert_eq!(sw, "v1 stable/app/sw.js");
+        let landing_page = fs::read_to_string(dir.join("myapp/index.html")).unwrap();
+        assert_eq!(landing_page, "v1 index.html");
+    }
+
+    #[tokio::test]
+    async fn activate_keeps_previous_release_when_copy_fails() {
+        let mut dir = env::temp_dir();
+        dir.push("woz-release-fail-test");
+        fs::remove_dir_all(&dir).ok();
+        let target = FailingTarget {
+            target: DirectoryTarget::new(dir.clone(), None),
+            fail_to: "myapp/app/index.html",
+            failed: AtomicBool::new(false),
+        };
+
+        write_release(&dir, "v1");
+        write_release(&dir, "v2");
+        let mut releases = Releases::default();
+        releases.add("v1");
+        releases.current = Some(String::from("v1"));
+        releases.add("v2");
+        assert!(releases.activate(&target, "myapp", "v2").await.is_err());
+
+        // Nothing points at the new release
+        assert_eq!(releases.current, Some(String::from("v1")));
+        assert!(Releases::fetch(&target, "myapp").await.unwrap().current.is_none());
+        for (public, source) in STABLE_FILES.iter() {
+            assert_eq!(fs::read_to_string(dir.join("myapp").join(public)).unwrap(),
+                       format!("v1 {}", source));
+        }
+
+        releases.activate(&target, "myapp", "v2").await.unwrap();
+        assert_eq!(Releases::fetch(&target, "myapp").await.unwrap().current, Some(String::from("v2")));
+        assert_eq!(fs::read_to_string(dir.join("myapp/app/sw.js")).unwrap(), "v2 stable/app/sw.js");
+    }
+}
@@ -0,0 +1,299 @@
+use std::io::Read;
+
+use async_trait::async_trait;
+use failure::Error;
+use failure::ResultExt;
+use flate2::read::GzDecoder;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rusoto_core::{ByteStream, Region, RusotoError};
+use rusoto_core::request::HttpClient;
+use rusoto_credential::{DefaultCredentialsProvider, StaticProvider};
+use rusoto_s3::*;
+use tokio::io::AsyncReadExt;
+
+use crate::config::S3Config;
+use crate::file_upload::FileUpload;
+use super::DeployTarget;
+
+
+// Characters left as is in the copy source header. Everything other
+// than the path separator and unreserved characters is escaped.
+const COPY_SOURCE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Deploys to an S3 bucket or any service with an S3 compatible API
+/// such as MinIO. Files are stored gzipped.
+pub struct S3Target {
+    client: S3Client,
+    bucket: String,
+    prefix: Option<String>,
+    public_url: String,
+}
+
+impl S3Target {
+    pub fn new(client: S3Client,
+               bucket: &str,
+               prefix: Option<String>,
+               public_url: &str) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_owned(),
+            prefix,
+            public_url: public_url.trim_end_matches('/').to_owned()
+        }
+    }
+
+    /// Returns the region to connect to. Setting an endpoint uses a
+    /// custom region so any S3 compatible service can be used.
+    fn region(conf: &S3Config) -> Result<Region, Error> {
+        let region_name = conf.region.clone().unwrap_or_else(|| String::from("us-east-1"));
+        let region = match &conf.endpoint {
+            Some(endpoint) => Region::Custom {
+                name: region_name,
+                endpoint: endpoint.to_owned()
+            },
+            None => region_name.parse::<Region>()
+                .context(format!("Invalid S3 region {}", region_name))?
+        };
+        Ok(region)
+    }
+
+    /// Creates the target from the `[deploy]` section of the woz
+    /// config. Credentials in the config are used if present,
+    /// otherwise they are looked up from the environment or
+    /// ~/.aws/credentials.
+    pub fn from_config(conf: &S3Config) -> Result<Self, Error> {
+        let region = Self::region(conf)?;
+        let public_url = match (&conf.public_url, &conf.endpoint) {
+            (Some(url), _) => url.to_owned(),
+            (None, Some(endpoint)) => format!("{}/{}", endpoint.trim_end_matches('/'), conf.bucket),
+            (None, None) => format!("https://{}.s3.{}.amazonaws.com", conf.bucket, region.name())
+        };
+
+        let dispatcher = HttpClient::new().context("Failed to make an HttpClient")?;
+        let client = match (&conf.access_key_id, &conf.secret_access_key) {
+            (Some(key), Some(secret)) => S3Client::new_with(
+                dispatcher,
+                StaticProvider::new_minimal(key.to_owned(), secret.to_owned()),
+                region
+            ),
+            (None, None) => S3Client::new_with(
+                dispatcher,
+                DefaultCredentialsProvider::new().context("Failed to load AWS credentials")?,
+                region
+            ),
+            _ => return Err(format_err!("Both access_key_id and secret_access_key must be set"))
+        };
+
+        Ok(Self::new(client, &conf.bucket, conf.prefix.clone(), &public_url))
+    }
+}
+
+#[async_trait]
+impl DeployTarget for S3Target {
+    fn key_prefix(&self, project_id: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{}/{}", prefix.trim_matches('/'), project_id),
+            None => project_id.to_owned()
+        }
+    }
+
+    fn url(&self, project_id: &str) -> String {
+        format!("{}/{}/index.html", self.public_url, self.key_prefix(project_id))
+    }
+
+    async fn put(&self, file: &FileUpload) -> Result<(), Error> {
+        let req = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: file.filename.clone(),
+            body: Some(ByteStream::from(file.gzip()?)),
+            content_type: Some(file.mimetype.clone()),
+            content_encoding: Some(String::from("gzip")),
+            ..Default::default()
+        };
+        self.client.put_object(req).await
+            .context(format!("Failed to upload {}", file.filename))?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let req = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_owned(),
+            ..Default::default()
+        };
+        let output = match self.client.get_object(req).await {
+            Ok(output) => output,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+            // Some S3 compatible services respond without an error code
+            Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => return Ok(None),
+            // Without s3:ListBucket, which woz.sh credentials don't
+            // have, S3 denies access to keys that don't exist rather
+            // than saying they're missing
+            Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 403 => {
+                debug!("Access to {} was denied, treating it as missing", key);
+                return Ok(None);
+            },
+            Err(e) => return Err(e).context(format!("Failed to download {}", key))?
+        };
+
+        let mut bytes = Vec::new();
+        if let Some(body) = output.body {
+            body.into_async_read()
+                .read_to_end(&mut bytes)
+                .await
+                .context(format!("Failed to read {}", key))?;
+        }
+
+        // Files are uploaded gzipped, some clients decompress the
+        // response and some don't
+        if output.content_encoding.as_deref() == Some("gzip") && bytes.starts_with(&[0x1f, 0x8b]) {
+            let mut decoded = Vec::new();
+            GzDecoder::new(bytes.as_slice())
+                .read_to_end(&mut decoded)
+                .context(format!("Failed to decompress {}", key))?;
+            bytes = decoded;
+        }
+        Ok(Some(bytes))
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), Error> {
+        // The copy keeps the content type and encoding of the source
+        let req = CopyObjectRequest {
+            bucket: self.bucket.clone(),
+            key: to.to_owned(),
+            copy_source: utf8_percent_encode(&format!("{}/{}", self.bucket, from), COPY_SOURCE)
+                .to_string(),
+            ..Default::default()
+        };
+        self.client.copy_object(req).await
+            .context(format!("Failed to copy {} to {}", from, to))?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod s3_tests {
+    use super::*;
+    use rusoto_mock::MockRequestDispatcher;
+    use rusoto_credential::AwsCredentials;
+
+    fn mock_target(dispatcher: MockRequestDispatcher) -> S3Target {
+        let client = S3Client::new_with(
+            dispatcher,
+            StaticProvider::from(AwsCredentials::default()),
+            Region::Custom {
+                name: String::from("us-east-1"),
+                endpoint: String::from("http://localhost:9000")
+            }
+        );
+        S3Target::new(client, "apps", Some(String::from("team/")), "http://localhost:9000/apps/")
+    }
+
+    #[test]
+    fn url_works() {
+        let target = mock_target(MockRequestDispatcher::default());
+        assert_eq!(target.key_prefix("myapp"), "team/myapp");
+        assert_eq!(target.url("myapp"), "http://localhost:9000/apps/team/myapp/index.html");
+    }
+
+    #[test]
+    fn from_config_works() {
+        let conf = S3Config {
+            bucket: String::from("apps"),
+            region: None,
+            endpoint: Some(String::from("http://localhost:9000/")),
+            access_key_id: Some(String::from("key")),
+            secret_access_key: Some(String::from("secret")),
+            prefix: None,
+            public_url: None,
+        };
+        let target = S3Target::from_config(&conf).unwrap();
+        assert_eq!(target.url("myapp"), "http://localhost:9000/apps/myapp/index.html");
+
+        let conf = S3Config { secret_access_key: None, ..conf };
+        assert!(S3Target::from_config(&conf).is_err());
+    }
+
+    #[tokio::test]
+    async fn put_works() {
+        let dispatcher = MockRequestDispatcher::default()
+            .with_request_checker(|req| {
+                assert_eq!(req.method(), "PUT");
+                assert_eq!(req.path(), "/apps/team/myapp/index.html");
+                assert_eq!(req.headers()["content-encoding"], vec![b"gzip".to_vec()]);
+                assert_eq!(req.headers()["content-type"], vec![b"text/html".to_vec()]);
+            });
+        let target = mock_target(dispatcher);
+        let file = FileUpload::new(
+            String::from("team/myapp/index.html"),
+            String::from("text/html"),
+            b"<html></html>".to_vec()
+        );
+        target.put(&file).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_works() {
+        let file = FileUpload::new(
+            String::from("team/myapp/.woz-manifest.json"),
+            String::from("application/json"),
+            b"{}".to_vec()
+        );
+        let dispatcher = MockRequestDispatcher::default()
+            .with_body("{}")
+            .with_request_checker(|req| {
+                assert_eq!(req.method(), "GET");
+                assert_eq!(req.path(), "/apps/team/myapp/.woz-manifest.json");
+            });
+        let target = mock_target(dispatcher);
+        assert_eq!(target.get(&file.filename).await.unwrap().unwrap(), b"{}");
+
+        let target = mock_target(MockRequestDispatcher::with_status(404).with_body(
+            "<Error><Code>NoSuchKey</Code><Message>Not found</Message></Error>"
+        ));
+        assert!(target.get(&file.filename).await.unwrap().is_none());
+
+        let target = mock_target(MockRequestDispatcher::with_status(403).with_body(
+            "<Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>"
+        ));
+        assert!(target.get(&file.filename).await.unwrap().is_none());
+
+        let target = mock_target(MockRequestDispatcher::with_status(500).with_body(
+            "<Error><Code>InternalError</Code><Message>Internal Error</Message></Error>"
+        ));
+        assert!(target.get(&file.filename).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn copy_works() {
+        let dispatcher = MockRequestDispatcher::default()
+            .with_body("<CopyObjectResult><ETag>\"abc\"</ETag></CopyObjectResult>")
+            .with_request_checker(|req| {
+                assert_eq!(req.method(), "PUT");
+                assert_eq!(req.path(), "/apps/team/myapp/releases/v2/app.js");
+                assert_eq!(req.headers()["x-amz-copy-source"],
+                           vec![b"apps/team/myapp/releases/v1/app.js".to_vec()]);
+            });
+        let target = mock_target(dispatcher);
+        target.copy("team/myapp/releases/v1/app.js", "team/myapp/releases/v2/app.js").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn copy_encodes_source() {
+        let dispatcher = MockRequestDispatcher::default()
+            .with_body("<CopyObjectResult><ETag>\"abc\"</ETag></CopyObjectResult>")
+            .with_request_checker(|req| {
+                assert_eq!(req.headers()["x-amz-copy-source"],
+                           vec![b"apps/team/myapp/releases/v1/img/my%20icon%2B1.png".to_vec()]);
+            });
+        let target = mock_target(dispatcher);
+        target.copy("team/myapp/releases/v1/img/my icon+1.png", "team/myapp/app/img/my icon+1.png")
+            .await.unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+use async_trait::async_trait;
+use failure::Error;
+use failure::ResultExt;
+
+use crate::cache::FileCache;
+use crate::config::{S3_BUCKET_NAME, SCHEME, NETLOC, MAX_APP_SIZE_MB};
+use crate::file_upload::FileUpload;
+use crate::upload_client::{self, AuthOptions};
+use super::DeployTarget;
+use super::s3::S3Target;
+
+
+/// Deploys to the woz.sh service. Files are stored in the woz S3
+/// bucket under the user's cognito identity ID using temporary
+/// credentials for the logged in user.
+pub struct WozTarget {
+    s3: S3Target
+}
+
+impl WozTarget {
+    /// Logs in the user if needed to get credentials for uploading
+    pub async fn new(cache: &FileCache, auth: &AuthOptions) -> Result<Self, Error> {
+        let s3_client = upload_client::authenticated_client(cache, auth)
+            .await
+            .context("Unable to initialize upload client")?;
+
+        let identity_id = cache.get("identity")
+            .context("Unable to retrieve user ID")?;
+
+        // All app files will be prefixed in the s3 bucket by the user's
+        // cognito identity ID and project_id
+        let s3 = S3Target::new(
+            s3_client,
+            S3_BUCKET_NAME,
+            Some(identity_id),
+            &format!("{}://{}", SCHEME, NETLOC)
+        );
+        Ok(Self { s3 })
+    }
+}
+
+#[async_trait]
+impl DeployTarget for WozTarget {
+    fn key_prefix(&self, project_id: &str) -> String {
+        self.s3.key_prefix(project_id)
+    }
+
+    fn url(&self, project_id: &str) -> String {
+        self.s3.url(project_id)
+    }
+
+    fn max_size_mb(&self) -> Option<usize> {
+        Some(MAX_APP_SIZE_MB)
+    }
+
+    async fn put(&self, file: &FileUpload) -> Result<(), Error> {
+        self.s3.put(file).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.s3.get(key).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), Error> {
+        self.s3.copy(from, to).await
+    }
+}
@@ -0,0 +1,122 @@
+//! Errors that can be reported to the user with a stable exit code.
+use std::fmt;
+
+use failure::{Context, Error, Fail};
+use rusoto_core::RusotoError;
+
+use crate::prompt::InteractionRequired;
+
+
+/// Exit code for errors that don't fall into a category
+pub const DEFAULT_EXIT_CODE: i32 = 1;
+
+/// Categories of failures with a human readable message. Wrap other
+/// errors with `.context(WozError::...)` to keep their cause.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WozError {
+    /// The woz config is missing or invalid
+    Config(String),
+    /// Logging in or using the user's account failed
+    Auth(String),
+    /// The user needs to verify their email before logging in
+    EmailNotVerified(String),
+    /// A request couldn't reach the service
+    Network(String),
+    /// Compiling or bundling the app failed
+    Build(String),
+    /// Deploying the app failed
+    Upload(String),
+}
+
+impl WozError {
+    /// Returns the exit code for the category of error. These are
+    /// part of the cli's interface so never change them.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            WozError::Config(_) => 2,
+            WozError::Auth(_) | WozError::EmailNotVerified(_) => 4,
+            WozError::Network(_) => 5,
+            WozError::Build(_) => 6,
+            WozError::Upload(_) => 7,
+        }
+    }
+
+    /// Converts a failed AWS request, reporting connection problems as
+    /// network errors and everything else using `category`
+    pub fn from_rusoto<E>(error: RusotoError<E>, category: fn(String) -> WozError) -> WozError
+        where E: std::error::Error + 'static
+    {
+        match error {
+            RusotoError::HttpDispatch(e) => WozError::Network(
+                format!("Unable to reach woz.sh, check your internet connection: {}", e)
+            ),
+            e => category(e.to_string())
+        }
+    }
+}
+
+impl fmt::Display for WozError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WozError::Config(msg)
+                | WozError::Auth(msg)
+                | WozError::EmailNotVerified(msg)
+                | WozError::Network(msg)
+                | WozError::Build(msg)
+                | WozError::Upload(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+impl Fail for WozError {}
+
+/// Exit code when input was needed but woz is running with
+/// --non-interactive or without a terminal
+pub const INTERACTION_REQUIRED_EXIT_CODE: i32 = 3;
+
+/// Returns the exit code for the outermost categorized error
+pub fn exit_code(e: &Error) -> i32 {
+    for cause in e.iter_chain() {
+        if let Some(error) = cause.downcast_ref::<WozError>() {
+            return error.exit_code();
+        }
+        if let Some(context) = cause.downcast_ref::<Context<WozError>>() {
+            return context.get_context().exit_code();
+        }
+        if cause.downcast_ref::<InteractionRequired>().is_some() {
+            return INTERACTION_REQUIRED_EXIT_CODE;
+        }
+    }
+    DEFAULT_EXIT_CODE
+}
+
+
+#[cfg(test)]
+mod error_tests {
+    use super::*;
+    use failure::ResultExt;
+
+    #[test]
+    fn exit_code_works() {
+        let error: Error = format_err!("Something went wrong");
+        assert_eq!(exit_code(&error), DEFAULT_EXIT_CODE);
+
+        let error: Error = WozError::Auth(String::from("Login failed")).into();
+        assert_eq!(exit_code(&error), 4);
+
+        // Categories are found through context added on top
+        let result: Result<(), Error> = Err(format_err!("Invalid toml"));
+        let error = result
+            .context(WozError::Config(String::from("Failed to parse woz config")))
+            .context("Failed to build app")
+            .unwrap_err();
+        assert_eq!(exit_code(&error.into()), 2);
+    }
+
+    #[test]
+    fn from_rusoto_works() {
+        let error: RusotoError<std::io::Error> = RusotoError::Validation(String::from("Bad request"));
+        assert_eq!(WozError::from_rusoto(error, WozError::Upload),
+                   WozError::Upload(String::from("Bad request")));
+    }
+}
@@ -1,3 +1,21 @@
+use std::io::Write;
+
+use failure::Error;
+use failure::ResultExt;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use ring::digest::{digest, SHA256};
+
+
+/// Returns the hex encoded SHA-256 digest of the bytes
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    digest(&SHA256, bytes)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
 /// Represents a file to be uploaded. File contents are held in memory
 /// as a vector of bytes. This may not be desireable for very large
 /// files...
@@ -12,4 +30,17 @@ impl FileUpload {
     pub fn new(filename: String, mimetype: String, bytes: Vec<u8>) -> Self {
         Self {filename, mimetype, bytes}
     }
+
+    /// Returns the gzip compressed file contents
+    pub fn gzip(&self) -> Result<Vec<u8>, Error> {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&self.bytes).context("Failed to gzip encode bytes")?;
+        Ok(gzip.finish().context("Failed to gzip file")?)
+    }
+
+    /// Returns a hash of the file contents used to tell whether the
+    /// file changed since it was last deployed
+    pub fn hash(&self) -> String {
+        sha256_hex(&self.bytes)
+    }
 }
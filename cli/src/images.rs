@@ -0,0 +1,274 @@
+//! Generates icons and splashscreens from a single source image.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use failure::Error;
+use failure::ResultExt;
+use image::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+use image::imageops::{self, FilterType};
+
+use crate::file_upload::sha256_hex;
+
+
+// Bump this when the way images are generated changes so that
+// previously cached images are not used
+const GENERATOR_VERSION: u32 = 1;
+
+// Fraction of the shortest side of a splashscreen taken up by the icon
+const SPLASHSCREEN_ICON_SCALE: f32 = 0.4;
+
+enum Decoded {
+    Raster(DynamicImage),
+    Svg(usvg::Tree),
+}
+
+/// A high resolution PNG or SVG image that icons and splashscreens
+/// are generated from. The image is only decoded if something needs
+/// to be generated.
+pub struct SourceImage {
+    path: PathBuf,
+    bytes: Vec<u8>,
+    hash: String,
+}
+
+impl SourceImage {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let bytes = fs::read(path)
+            .context(format!("Failed to read icon {}", path.display()))?;
+        let hash = sha256_hex(&bytes);
+        Ok(Self { path: path.to_path_buf(), bytes, hash })
+    }
+
+    fn is_svg(&self) -> bool {
+        self.path.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+    }
+
+    fn decode(&self) -> Result<Decoded, Error> {
+        let decoded = if self.is_svg() {
+            let opts = usvg::Options::default();
+            let tree = usvg::Tree::from_data(&self.bytes, &opts.to_ref())
+                .map_err(|e| format_err!("Failed to parse icon {}: {}", self.path.display(), e))?;
+            Decoded::Svg(tree)
+        } else {
+            let img = image::load_from_memory(&self.bytes)
+                .context(format!("Failed to decode icon {}", self.path.display()))?;
+            Decoded::Raster(img)
+        };
+        Ok(decoded)
+    }
+}
+
+/// Returns the opaque RGBA color for a hex color such as `#fff` or
+/// `#1a2b3c`, a CSS color name such as `navy` or an `rgb(...)` color
+pub fn parse_color(color: &str) -> Result<Rgba<u8>, Error> {
+    let unsupported = || format_err!(
+        "Unsupported color {}, use a hex color like #1a2b3c, a CSS color name like navy or rgb(26, 43, 60)",
+        color
+    );
+    let color = color.trim();
+    if !color.starts_with('#') {
+        let name = color.to_lowercase();
+        // svgtypes only knows the CSS3 color names, this is the one added since
+        if name == "rebeccapurple" {
+            return Ok(Rgba([0x66, 0x33, 0x99, 255]));
+        }
+        let parsed: svgtypes::Color = name.parse().map_err(|_| unsupported())?;
+        return Ok(Rgba([parsed.red, parsed.green, parsed.blue, 255]));
+    }
+
+    let hex = &color[1..];
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(unsupported());
+    }
+    let hex = match hex.len() {
+        3 => hex.chars().flat_map(|c| vec![c, c]).collect::<String>(),
+        6 => hex.to_owned(),
+        _ => return Err(unsupported())
+    };
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16)
+        .map_err(|_| unsupported());
+    Ok(Rgba([channel(0)?, channel(2)?, channel(4)?, 255]))
+}
+
+/// Renders the image scaled to fit a `size` by `size` square,
+/// centered on a transparent background
+fn render_square(decoded: &Decoded, size: u32) -> Result<RgbaImage, Error> {
+    match decoded {
+        Decoded::Raster(img) => {
+            let resized = img.resize(size, size, FilterType::Lanczos3).to_rgba8();
+            let mut canvas = RgbaImage::new(size, size);
+            imageops::overlay(&mut canvas,
+                              &resized,
+                              (size - resized.width()) / 2,
+                              (size - resized.height()) / 2);
+            Ok(canvas)
+        },
+        Decoded::Svg(tree) => {
+            let fit_to = usvg::FitTo::Size(size, size);
+            let fitted = fit_to.fit_to(tree.svg_node().size.to_screen_size())
+                .ok_or_else(|| format_err!("Icon has an invalid size"))?;
+            let transform = tiny_skia::Transform::from_translate(
+                ((size - fitted.width()) / 2) as f32,
+                ((size - fitted.height()) / 2) as f32
+            );
+            let mut pixmap = tiny_skia::Pixmap::new(size, size)
+                .ok_or_else(|| format_err!("Invalid icon size {}", size))?;
+            resvg::render(tree, fit_to, transform, pixmap.as_mut())
+                .ok_or_else(|| format_err!("Failed to render icon"))?;
+
+            // Pixmaps use premultiplied alpha
+            let mut data = pixmap.take();
+            for px in data.chunks_mut(4) {
+                let alpha = u32::from(px[3]);
+                if alpha > 0 && alpha < 255 {
+                    for c in px[..3].iter_mut() {
+                        *c = (u32::from(*c) * 255 / alpha) as u8;
+                    }
+                }
+            }
+            RgbaImage::from_raw(size, size, data)
+                .ok_or_else(|| format_err!("Failed to render icon"))
+        }
+    }
+}
+
+fn encode_png(img: RgbaImage) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    DynamicImage::ImageRgba8(img)
+        .write_to(&mut bytes, ImageOutputFormat::Png)
+        .context("Failed to encode PNG")?;
+    Ok(bytes)
+}
+
+/// Stores generated images on disk so they are only generated again
+/// when the source image or the requested size changes.
+pub struct ImageCache {
+    dir: PathBuf,
+}
+
+impl ImageCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn get_or_generate<F>(&self, key: &str, generate: F) -> Result<Vec<u8>, Error>
+        where F: FnOnce() -> Result<Vec<u8>, Error>
+    {
+        let path = self.dir.join(format!("{}.png", sha256_hex(key.as_bytes())));
+        if let Ok(bytes) = fs::read(&path) {
+            return Ok(bytes);
+        }
+
+        let bytes = generate()?;
+        // Caching is best effort, failing to write only means the
+        // image is generated again next time
+        fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(&path, &bytes))
+            .ok();
+        Ok(bytes)
+    }
+}
+
+fn decoded<'a>(source: &SourceImage, slot: &'a mut Option<Decoded>) -> Result<&'a Decoded, Error> {
+    if slot.is_none() {
+        *slot = Some(source.decode()?);
+    }
+    Ok(slot.as_ref().unwrap())
+}
+
+/// Returns a square PNG icon for each of the named sizes
+pub fn icons(source: &SourceImage, sizes: &[(&str, u32)],
+             cache: &ImageCache) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let mut slot = None;
+    let mut images = Vec::new();
+    for (name, size) in sizes.iter() {
+        let key = format!("{}:{}:icon:{}", GENERATOR_VERSION, source.hash, size);
+        let bytes = cache.get_or_generate(&key, || {
+            encode_png(render_square(decoded(source, &mut slot)?, *size)?)
+        })?;
+        images.push((name.to_string(), bytes));
+    }
+    Ok(images)
+}
+
+/// Returns a PNG splashscreen for each of the named sizes with the
+/// icon centered on the background color
+pub fn splashscreens(source: &SourceImage, sizes: &[(&str, u32, u32)], bg_color: &str,
+                     cache: &ImageCache) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let bg = parse_color(bg_color).context("Invalid bg_color for splashscreens")?;
+    let mut slot = None;
+    let mut images = Vec::new();
+    for (name, width, height) in sizes.iter() {
+        let key = format!("{}:{}:splashscreen:{}x{}:{:?}",
+                          GENERATOR_VERSION, source.hash, width, height, bg.0);
+        let bytes = cache.get_or_generate(&key, || {
+            let icon_size = (*width.min(height) as f32 * SPLASHSCREEN_ICON_SCALE) as u32;
+            let icon = render_square(decoded(source, &mut slot)?, icon_size)?;
+            let mut canvas = RgbaImage::from_pixel(*width, *height, bg);
+            imageops::overlay(&mut canvas, &icon, (width - icon_size) / 2, (height - icon_size) / 2);
+            encode_png(canvas)
+        })?;
+        images.push((name.to_string(), bytes));
+    }
+    Ok(images)
+}
+
+
+#[cfg(test)]
+mod images_tests {
+    use super::*;
+    use std::env;
+
+    fn cache(name: &str) -> ImageCache {
+        let mut dir = env::temp_dir();
+        dir.push(name);
+        fs::remove_dir_all(&dir).ok();
+        ImageCache::new(dir)
+    }
+
+    #[test]
+    fn parse_color_works() {
+        assert_eq!(parse_color("#1a2b3c").unwrap(), Rgba([0x1a, 0x2b, 0x3c, 255]));
+        assert_eq!(parse_color("#fff").unwrap(), Rgba([255, 255, 255, 255]));
+        assert_eq!(parse_color("black").unwrap(), Rgba([0, 0, 0, 255]));
+        assert_eq!(parse_color("RebeccaPurple").unwrap(), Rgba([0x66, 0x33, 0x99, 255]));
+        assert_eq!(parse_color("DarkSlateGray").unwrap(), Rgba([0x2f, 0x4f, 0x4f, 255]));
+        assert_eq!(parse_color("navy").unwrap(), Rgba([0, 0, 0x80, 255]));
+        assert_eq!(parse_color("rgb(26, 43, 60)").unwrap(), Rgba([0x1a, 0x2b, 0x3c, 255]));
+        assert!(parse_color("notacolor").is_err());
+        assert!(parse_color("#12345").is_err());
+        assert!(parse_color("#gggggg").is_err());
+    }
+
+    #[test]
+    fn icons_from_svg_works() {
+        let source = SourceImage::open(Path::new("test_data/icon.svg")).unwrap();
+        let cache = cache("woz-icons-test");
+        let images = icons(&source, &[("48x48", 48), ("512x512", 512)], &cache).unwrap();
+
+        assert_eq!(images.len(), 2);
+        let icon = image::load_from_memory(&images[1].1).unwrap().to_rgba8();
+        assert_eq!(icon.dimensions(), (512, 512));
+        // The icon is a red circle with a transparent background
+        assert_eq!(icon.get_pixel(256, 256), &Rgba([255, 0, 0, 255]));
+        assert_eq!(icon.get_pixel(0, 0)[3], 0);
+
+        // Generated images are reused
+        assert_eq!(fs::read_dir(&cache.dir).unwrap().count(), 2);
+        assert_eq!(icons(&source, &[("48x48", 48)], &cache).unwrap()[0].1, images[0].1);
+    }
+
+    #[test]
+    fn splashscreens_works() {
+        let source = SourceImage::open(Path::new("test_data/icon.svg")).unwrap();
+        let cache = cache("woz-splashscreens-test");
+        let images = splashscreens(&source, &[("iphone5", 640, 1136)], "#0000ff", &cache).unwrap();
+
+        let splash = image::load_from_memory(&images[0].1).unwrap().to_rgba8();
+        assert_eq!(splash.dimensions(), (640, 1136));
+        assert_eq!(splash.get_pixel(0, 0), &Rgba([0, 0, 255, 255]));
+        assert_eq!(splash.get_pixel(320, 568), &Rgba([255, 0, 0, 255]));
+    }
+}
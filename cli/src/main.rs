@@ -6,8 +6,8 @@ use std::fs;
 use std::str;
 use std::env;
 use std::process;
-use rand::{thread_rng, Rng};
-use rand::distributions::Alphanumeric;
+use std::net::SocketAddr;
+use std::time::Instant;
 
 #[macro_use] extern crate clap;
 use clap::App;
@@ -16,11 +16,15 @@ use clap::App;
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate serde_json;
 #[macro_use] extern crate failure;
+#[macro_use] extern crate log;
 
 use failure::Error;
 use failure::ResultExt;
+use rusoto_core::RusotoError;
+use rusoto_cognito_identity::CognitoIdentityClient;
 use rusoto_cognito_idp::*;
 
+#[macro_use] mod output;
 mod prompt;
 mod account;
 mod config;
@@ -30,26 +34,43 @@ mod builder;
 mod components;
 mod upload_client;
 mod file_upload;
+mod update;
+mod server;
+mod watch;
+mod deploy;
+mod error;
+mod images;
+mod size;
+mod wasm_binary;
+mod wasm_opt;
+mod project;
 
 use config::*;
 use template::load_templates;
 use cache::FileCache;
-use builder::AppBuilder;
-use components::wasm::WasmComponent;
-use components::pwa::PwaComponent;
-use components::icon::IconComponent;
-use components::splashscreen::SplashscreenComponent;
-use components::landing_page::LandingPageComponent;
+use error::WozError;
+use upload_client::AuthOptions;
+use output::BuildReport;
+use project::Project;
+use deploy::release::{self, Releases};
 
 
 enum Command {
     Build,
+    Size,
+    Serve,
     Deploy,
     Init,
     NewProject,
     Setup,
     Signup,
+    Verify,
+    Account,
+    Logout,
+    Whoami,
     Update,
+    Rollback,
+    Releases,
     Unknown,
 }
 
@@ -57,35 +78,40 @@ impl From<&str> for Command {
     fn from(s: &str) -> Command {
         match s {
             "build" => Command::Build,
+            "size" => Command::Size,
+            "serve" => Command::Serve,
             "deploy" => Command::Deploy,
             "init" => Command::Init,
             "new" => Command::NewProject,
             "setup" => Command::Setup,
             "signup" => Command::Signup,
+            "verify" => Command::Verify,
+            "account" => Command::Account,
+            "logout" => Command::Logout,
+            "whoami" => Command::Whoami,
             "update" => Command::Update,
+            "rollback" => Command::Rollback,
+            "releases" => Command::Releases,
             _ => Command::Unknown
         }
     }
 }
 
-fn random_version() -> String {
-    thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(7)
-        .collect()
-}
-
-#[test]
-fn random_version_works() {
-    assert_eq!(7, random_version().len());
-}
-
 async fn run() -> Result<(), Error> {
     let handlebars = load_templates().context("Failed to load templates")?;
 
     let yaml = load_yaml!("cli.yaml");
     let app = App::from_yaml(yaml).version(&crate_version!()[..]);
     let input = app.get_matches();
+    output::set_json(input.value_of("output") == Some("json"));
+
+    // Warnings are shown by default, each -v shows another level
+    stderrlog::new()
+        .module(module_path!())
+        .verbosity(1 + input.occurrences_of("verbose") as usize)
+        .quiet(input.is_present("quiet"))
+        .init()
+        .context("Failed to initialize logging")?;
 
     // Get the project path either from being passed in as an arg or
     // default to the current directory
@@ -93,22 +119,37 @@ async fn run() -> Result<(), Error> {
         .map_or(env::current_dir(),
                 |arg| Ok(PathBuf::from(&arg.vals[0])))
         .context("Failed to get project path")?;
-    println!("Using project path {}", project_path.to_str().unwrap());
+    debug!("Using project path {}", project_path.display());
 
     let conf_path = input.args.get("config")
         .map_or({let mut c_path = project_path.clone();
                  c_path.push("woz.toml");
                  c_path},
                 |arg| PathBuf::from(&arg.vals[0]));
-    println!("Using config path {}", conf_path.to_str().unwrap());
+    debug!("Using config path {}", conf_path.display());
 
     let home_path = input.args.get("home")
         .map_or(default_home_path(),
                 |arg| Ok(PathBuf::from(&arg.vals[0])))
         .context("Failed to get woz home path")?;
-    let encryption_key = FileCache::make_key(ENCRYPTION_PASSWORD, ENCRYPTION_SALT);
-    let cache = FileCache::new(encryption_key, home_path.clone());
-    println!("Using home path {}", home_path.to_str().unwrap());
+    let profile = input.value_of("profile")
+        .map(String::from)
+        .or_else(|| env::var(PROFILE_VAR).ok());
+    let profile_path = profile_path(&home_path, profile.as_deref())?;
+    let profile_name = profile.unwrap_or_else(|| String::from("default"));
+    let passphrase = env::var(cache::PASSPHRASE_VAR).ok();
+    let encryption_key = FileCache::installation_key(&home_path, passphrase.as_deref())
+        .context("Failed to load encryption key")?;
+    let cache = FileCache::new(encryption_key, profile_path)
+        .legacy_key(FileCache::make_legacy_key(ENCRYPTION_PASSWORD, ENCRYPTION_SALT));
+    debug!("Using home path {}", home_path.display());
+
+    let non_interactive = input.is_present("non-interactive");
+    let auth = AuthOptions {
+        non_interactive,
+        token_file: input.value_of("token-file").map(PathBuf::from),
+        credentials_stdin: input.is_present("credentials-stdin"),
+    };
 
     if let Some(sub) = input.subcommand_name() {
         match Command::from(sub) {
@@ -118,60 +159,81 @@ async fn run() -> Result<(), Error> {
             // 3. A unique user ID
             // 4. A refresh token
             Command::Signup => {
-                fs::create_dir_all(&home_path).context("Failed to make home directory")?;
+                if non_interactive {
+                    return Err(prompt::InteractionRequired::new(
+                        "Signing up",
+                        "Run woz signup from a terminal"
+                    ).into());
+                }
 
-                // TODO first check if there is an existing installation
-                let values = prompt::signup();
-                let id_provider_client = account::anonymous_identity_provider_client();
-                let id_client = account::anonymous_identity_client();
+                if cache.get_encrypted("refresh_token").is_ok() {
+                    return Err(format_err!(
+                        "Profile {} is already logged in, run 'woz logout' or use --profile to sign up for another account",
+                        profile_name
+                    ));
+                }
 
-                let user_id = account::signup(&id_provider_client,
-                                              &values.email,
-                                              &values.username,
-                                              &values.password)
-                    .await
-                    .context("Signup failed")?
-                    .user_sub;
-
-                cache.set("user", user_id.as_bytes().to_vec())
-                    .context("Failed to add user ID to cache")?;
-
-                // Prompt the user to confirm they clicked the verification link
-                let mut email_verified = false;
-
-                while !email_verified {
-                    println!("Please check your inbox for an email to verify your account.");
-                    if prompt::is_email_verified() {
-                        // It's still possible for this to fail if the user
-                        // says they are verified, but they aren't
-                        account::setup(&id_provider_client,
-                                       &id_client,
-                                       &cache,
-                                       &values.username,
-                                       &values.password)
+                let id_provider_client = account::anonymous_identity_provider_client()?;
+                let id_client = account::anonymous_identity_client()?;
+
+                // Pick up where an earlier signup left off
+                let (username, password) = match cache.get(account::PENDING_SIGNUP_KEY) {
+                    Ok(username) => {
+                        say!("Resuming signup for {}", username);
+                        let password = prompt::password(&username)?;
+                        (username, password)
+                    },
+                    Err(_) => {
+                        let values = prompt::signup()?;
+                        let resp = account::signup(&id_provider_client,
+                                                   &values.email,
+                                                   &values.username,
+                                                   &values.password)
                             .await
-                            .or_else(|e| {
-                                match e {
-                                    InitiateAuthError::UserNotConfirmed(_) => {
-                                        println!("Auth failed, have you clicked the link in the verification email?");
-                                        email_verified = false;
-                                        Ok(())
-                                    },
-                                    _ => Err(e)
-                                }
-                            })
-                            .context("Failed to set up account")?;
+                            .context("Signup failed")?;
+
+                        cache.set("user", resp.user_sub.as_bytes().to_vec())
+                            .context("Failed to add user ID to cache")?;
+                        cache.set(account::PENDING_SIGNUP_KEY, values.username.as_bytes().to_vec())
+                            .context("Failed to add signup to cache")?;
 
-                        email_verified = true;
+                        if !resp.user_confirmed {
+                            confirm_signup(&id_provider_client, &values.username).await?;
+                        }
+                        (values.username, values.password)
                     }
                 };
 
-                println!("Your account has been successfully set up! You can now deploy to your applications using 'woz deploy'");
+                finish_signup(&id_provider_client, &id_client, &cache, &username, &password).await?;
+                say!("Your account has been successfully set up! You can now deploy to your applications using 'woz deploy'");
+            },
+            Command::Verify => {
+                if non_interactive {
+                    return Err(prompt::InteractionRequired::new(
+                        "Verifying your email",
+                        "Run woz verify from a terminal"
+                    ).into());
+                }
+
+                let username = cache.get(account::PENDING_SIGNUP_KEY)
+                    .map_err(|_| format_err!("There is no signup to verify, run 'woz signup' to create an account"))?;
+                let id_provider_client = account::anonymous_identity_provider_client()?;
+                let id_client = account::anonymous_identity_client()?;
+
+                if input.subcommand_matches("verify").unwrap().is_present("resend") {
+                    resend_confirmation_code(&id_provider_client, &username).await?;
+                }
+
+                let password = prompt::password(&username)?;
+                finish_signup(&id_provider_client, &id_client, &cache, &username, &password).await?;
+                say!("Your account has been successfully set up! You can now deploy to your applications using 'woz deploy'");
             },
             Command::Setup => {
-                let values = prompt::login();
-                let id_provider_client = account::anonymous_identity_provider_client();
-                let id_client = account::anonymous_identity_client();
+                fs::create_dir_all(&home_path).context("Failed to make home directory")?;
+
+                let values = prompt::credentials(non_interactive, auth.credentials_stdin)?;
+                let id_provider_client = account::anonymous_identity_provider_client()?;
+                let id_client = account::anonymous_identity_client()?;
 
                 account::setup(&id_provider_client,
                                &id_client,
@@ -179,7 +241,125 @@ async fn run() -> Result<(), Error> {
                                &values.username,
                                &values.password)
                     .await
-                    .expect("Unable to login and perform local set up");
+                    .context("Unable to login and perform local set up")?;
+
+                let subcommand_args = input.subcommand_matches("setup").unwrap();
+                if let Some(path) = subcommand_args.value_of("export-token") {
+                    let refresh_token = cache.get_encrypted("refresh_token")
+                        .context("Failed to read refresh token")?;
+                    fs::write(path, refresh_token).context("Failed to write token file")?;
+                    say!("Refresh token written to {}, keep it secret", path);
+                }
+            },
+            Command::Account => {
+                if non_interactive {
+                    return Err(prompt::InteractionRequired::new(
+                        "Managing your account",
+                        "Run woz account from a terminal"
+                    ).into());
+                }
+
+                let id_provider_client = account::anonymous_identity_provider_client()?;
+                let account_args = input.subcommand_matches("account").unwrap();
+
+                match account_args.subcommand_name() {
+                    Some("reset-password") => {
+                        let username = prompt::username()?;
+                        let destination = account::forgot_password(&id_provider_client, &username)
+                            .await
+                            .context("Failed to reset password")?
+                            .code_delivery_details
+                            .and_then(|details| details.destination)
+                            .unwrap_or_else(|| String::from("your email"));
+                        say!("Sent a code to reset your password to {}", destination);
+
+                        let code = prompt::code()?;
+                        let password = prompt::new_password()?;
+                        account::confirm_forgot_password(&id_provider_client, &username, &code, &password)
+                            .await
+                            .context("Failed to reset password")?;
+                        say!("Your password has been reset, run 'woz setup' to log in with it");
+                    },
+                    Some("change-password") => {
+                        let access_token = upload_client::access_token(&cache, &id_provider_client, &auth)
+                            .await?;
+                        let previous_password = prompt::current_password()?;
+                        let proposed_password = prompt::new_password()?;
+                        account::change_password(&id_provider_client,
+                                                 &access_token,
+                                                 &previous_password,
+                                                 &proposed_password)
+                            .await
+                            .context("Failed to change password")?;
+                        say!("Your password has been changed");
+                    },
+                    Some("change-email") => {
+                        let access_token = upload_client::access_token(&cache, &id_provider_client, &auth)
+                            .await?;
+                        let email = prompt::email()?;
+                        let email = email.trim();
+                        account::change_email(&id_provider_client, &access_token, email)
+                            .await
+                            .context("Failed to change email")?;
+                        say!("Sent a code to verify your new email to {}", email);
+
+                        let code = prompt::code()?;
+                        account::verify_email(&id_provider_client, &access_token, &code)
+                            .await
+                            .context("Failed to verify email")?;
+                        say!("Your email has been changed to {}", email);
+                    },
+                    Some("delete") => {
+                        let access_token = upload_client::access_token(&cache, &id_provider_client, &auth)
+                            .await?;
+                        let confirm = || prompt::confirm("This permanently deletes your woz.sh account.",
+                                                         "delete");
+                        if account::delete_account(&id_provider_client, &cache, &access_token, confirm).await? {
+                            say!("Your account has been deleted");
+                        } else {
+                            say!("Your account was not deleted");
+                        }
+                    },
+                    // The account command is SubcommandRequiredElseHelp
+                    // so clap exits with the help before getting here
+                    _ => unreachable!()
+                };
+            },
+            Command::Logout => {
+                let mut removed = false;
+                for key in account::SESSION_KEYS.iter() {
+                    removed |= cache.remove(key)
+                        .context("Failed to remove cached login")?;
+                }
+                if removed {
+                    say!("Logged out of profile {}", profile_name);
+                } else {
+                    say!("Profile {} is not logged in", profile_name);
+                }
+            },
+            Command::Whoami => {
+                let refresh_token = upload_client::supplied_refresh_token(&auth)?
+                    .or_else(|| cache.get_encrypted("refresh_token").ok())
+                    .ok_or_else(|| format_err!(
+                        "Profile {} is not logged in, run 'woz setup' to log in", profile_name
+                    ))?;
+                let id_provider_client = account::anonymous_identity_provider_client()?;
+                let id_token = account::refresh_auth(&id_provider_client, &refresh_token)
+                    .await
+                    .context("Login expired, run 'woz setup' to log in again")?
+                    .authentication_result
+                    .and_then(|result| result.id_token)
+                    .ok_or_else(|| format_err!("Missing ID token"))?;
+                let claims = account::id_token_claims(&id_token)?;
+
+                say!("Profile: {}", profile_name);
+                say!("Username: {}", claims.username);
+                if let Some(email) = claims.email {
+                    say!("Email: {}", email);
+                }
+                if let Ok(identity_id) = cache.get("identity") {
+                    say!("Identity: {}", identity_id);
+                }
             },
             Command::NewProject => {
                 // This unwrap is safe because the cli preparses and
@@ -235,12 +415,12 @@ wasm_path=\"target/wasm32-unknown-unknown/release/{}.wasm\"
                     PathBuf::from(format!("{}/src/lib.rs", project_name))
                 ).context("Failed to create lib.rs")?;
                 default_lib_rs.write_all(DEFAULT_PROJECT_LIB_RS.as_bytes()).unwrap();
-                println!("New project created! Please cd to ./{}", project_name);
+                say!("New project created! Please cd to ./{}", project_name);
             },
             // Init should result in
             // 1. A config file in the current directory
             Command::Init => {
-                println!("Initializing current project directory...");
+                say!("Initializing current project directory...");
                 let cargo_conf = fs::read_to_string("./Config.toml")
                     .context("You must be in a cargo project")?
                     .parse::<toml::Value>()
@@ -265,147 +445,337 @@ wasm_path=\"target/wasm32-unknown-unknown/release/{}.wasm\"
 # wasm_path=\"target/wasm32-unknown-unknown/debug/seed_app.wasm\"
 ", project_name, project_name, project_name, project_name).as_bytes()).unwrap();
 
-                println!("Ready to be deployed with 'woz deploy'");
+                say!("Ready to be deployed with 'woz deploy'");
             },
-            Command::Build => {
-                println!("Building...");
-                let version = random_version();
-
-                // Load the woz config if present or use default config
-                let conf_str = fs::read_to_string(conf_path.clone())
-                    .context(format!("Couldn't find woz config file at {}",
-                                     conf_path.clone().to_str().unwrap()))?;
-                let conf: Config = toml::from_str(&conf_str)
-                    .context("Failed to parse woz config")?;
-
-                let ProjectId(project_id) = conf.project_id.clone();
-                let mut out_path = home_path.clone();
-                out_path.push(&project_id);
-                out_path.push("pkg");
-                fs::create_dir_all(&out_path).context("Failed to make pkg directory")?;
-
-                let mut wasm_path = project_path.clone();
-                wasm_path.push(conf.wasm_path.clone());
+            // Size builds the app the same way to report on it
+            Command::Build | Command::Size => {
+                let size_only = sub == "size";
+                let watch = input.subcommand_matches("build").is_some_and(|m| m.is_present("watch"));
+
+                // In watch mode the whole build is redone when the
+                // config changes or the previous build failed,
+                // otherwise only the affected components are rebuilt
+                loop {
+                    say!("Building...");
+                    let started = Instant::now();
+
+                    let project = Project::load(&conf_path, &project_path, &home_path)?;
+                    let (conf, project_id) = (&project.conf, &project.project_id);
+                    let url = conf.project_url.clone().unwrap_or(format!(
+                        "{}://{}/{}/index.html",
+                        SCHEME,
+                        NETLOC,
+                        project_id
+                    ));
+
+                    // Build the app with all the components
+                    let components = project.components(&url, &handlebars, None);
+                    let file_prefix = String::from(project.out_path.to_str().unwrap());
+                    let build_env = &project.build_env();
+                    let mut app = components.builder();
+
+                    // Building and waiting on file changes block, so
+                    // they're run with block_in_place like in serve
+                    let built = tokio::task::block_in_place(|| {
+                        app.build(&project_path, &file_prefix, build_env)
+                            .context("Failed to build app")
+                            .map_err(Error::from)
+                            .and_then(|_| {
+                                app.download().context("Failed to download files from the build")?;
+                                let report = BuildReport::new(project_id, app.version(), &url,
+                                                              &app.files(), &file_prefix,
+                                                              started.elapsed())?;
+                                if size_only {
+                                    size::print_table(&report);
+                                } else {
+                                    say!("App package directory can be found at {}", file_prefix);
+                                    say!("Built version {} with {}", app.version(), size::summary(&report));
+                                }
+                                // Only a build within budget emits its report so json
+                                // output is a single result or error
+                                size::enforce_budget(conf.budget.as_ref(), &report)?;
+                                if output::is_json() {
+                                    output::emit(&report)?;
+                                }
+                                Ok(())
+                            })
+                    });
 
-                let url = conf.project_url.clone().unwrap_or(format!(
-                    "{}://{}/{}/index.html",
-                    SCHEME,
-                    NETLOC,
-                    project_id
-                ));
+                    if !watch {
+                        built?;
+                        break;
+                    }
+                    if let Err(e) = &built {
+                        output::error(e);
+                    }
 
-                // Build the app with all the components
-                let landing_page_cmpnt = LandingPageComponent::new(
-                    &conf,
-                    &url,
-                    &handlebars
-                );
-                let wasm_cmpnt = WasmComponent::new(wasm_path, &out_path);
-                let pwa_cmpnt = PwaComponent::new(
-                    &conf,
-                    &url,
-                    &handlebars,
-                    &version
-                );
-                let icon_cmpnt = IconComponent::new(&conf);
-                let splashscreen_cmpnt = SplashscreenComponent::new(&conf);
-
-                let file_prefix = String::from(out_path.to_str().unwrap());
-                let build_env = &conf.env.to_owned().unwrap_or(Environment::Development);
-                let mut app = AppBuilder::new();
-                app
-                    .component(&landing_page_cmpnt)
-                    .component(&wasm_cmpnt)
-                    .component(&pwa_cmpnt)
-                    .component(&icon_cmpnt)
-                    .component(&splashscreen_cmpnt)
-                    .build(&project_path, &file_prefix, &build_env)
-                    .context("Failed to build app")?;
+                    let mut watch_paths = app.watch_paths(&project_path);
+                    watch_paths.push(conf_path.clone());
+                    let watcher = watch::FileWatcher::new(&watch_paths)?;
+                    say!("Watching for changes...");
+
+                    loop {
+                        let changed = tokio::task::block_in_place(|| watcher.wait())?;
+                        if built.is_err() || changed.iter().any(|p| p.ends_with("woz.toml") || p == &conf_path) {
+                            break;
+                        }
+
+                        say!("Rebuilding...");
+                        let started = Instant::now();
+                        let rebuilt = tokio::task::block_in_place(|| {
+                            app.rebuild(&changed, &project_path, &file_prefix, build_env)
+                                .context("Failed to rebuild app")
+                                .map_err(Error::from)
+                                .and_then(|_| {
+                                    app.download().context("Failed to download files from the build")?;
+                                    let report = BuildReport::new(project_id, app.version(), &url,
+                                                                  &app.files(), &file_prefix,
+                                                                  started.elapsed())?;
+                                    say!("App package directory updated to version {} with {}",
+                                         app.version(), size::summary(&report));
+                                    // Only a build within budget emits its report so json
+                                    // output is a single result or error
+                                    size::enforce_budget(conf.budget.as_ref(), &report)?;
+                                    if output::is_json() {
+                                        output::emit(&report)?;
+                                    }
+                                    Ok(())
+                                })
+                        });
+                        if let Err(e) = rebuilt {
+                            output::error(&e);
+                        }
+                    }
+                }
+            },
+            Command::Serve => {
+                let subcommand_args = input.subcommand_matches("serve").unwrap();
+                let port = subcommand_args.value_of("port")
+                    .unwrap_or("8080")
+                    .parse::<u16>()
+                    .context("Port must be a number")?;
+
+                // The server runs in the background while the app is
+                // rebuilt in response to file changes
+                let mut dev_server: Option<server::DevServer> = None;
+
+                // The whole build is redone when the config changes
+                // or the previous build failed, otherwise only the
+                // affected components are rebuilt
+                loop {
+                    say!("Building...");
+
+                    let project = Project::load(&conf_path, &project_path, &home_path)?;
+                    let project_id = &project.project_id;
+
+                    // Use the LAN address so the app can be opened from
+                    // other devices on the same network
+                    let lan_url = format!("http://{}:{}/{}/index.html",
+                                          server::lan_ip(),
+                                          port,
+                                          project_id);
+
+                    // Build the app with all the components
+                    let components = project.components(&lan_url, &handlebars,
+                                                        Some(server::LIVE_RELOAD_PATH));
+                    let build_env = &project.build_env();
+                    let mut app = components.builder();
+
+                    // Building blocks so let the runtime know the server
+                    // should keep running on other threads
+                    let built = tokio::task::block_in_place(|| {
+                        app.build(&project_path, project_id, build_env)
+                            .context("Failed to build app")
+                            .map_err(Error::from)
+                    });
+
+                    match (&built, &dev_server) {
+                        (Ok(_), Some(running)) => {
+                            running.set_files(app.files())?;
+                            running.reload();
+                            say!("App reloaded");
+                        },
+                        (Ok(_), None) => {
+                            let new_server = server::DevServer::new(project_id);
+                            new_server.set_files(app.files())?;
+
+                            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+                            let serving = new_server.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = serving.serve(addr).await {
+                                    output::error(&e);
+                                    std::process::exit(1);
+                                }
+                            });
+                            dev_server = Some(new_server);
+
+                            say!("Serving app at http://localhost:{}/{}/index.html", port, project_id);
+                            say!("On your local network at {}", lan_url);
+                            say!("Note: service workers are only enabled over localhost or https");
+                        },
+                        // The server is started after the first
+                        // successful build
+                        (Err(e), _) => output::error(e)
+                    }
 
-                app.download().context("Failed to download files from the build")?;
-                println!("App package directory can be found at {}", file_prefix);
+                    let mut watch_paths = app.watch_paths(&project_path);
+                    watch_paths.push(conf_path.clone());
+                    let watcher = watch::FileWatcher::new(&watch_paths)?;
+                    say!("Watching for changes...");
+
+                    loop {
+                        // Waiting on file changes blocks so let the
+                        // runtime know the server should keep running
+                        // on other threads
+                        let changed = tokio::task::block_in_place(|| watcher.wait())?;
+                        if built.is_err() || changed.iter().any(|p| p.ends_with("woz.toml") || p == &conf_path) {
+                            break;
+                        }
+
+                        say!("Rebuilding...");
+                        let rebuilt = tokio::task::block_in_place(|| {
+                            app.rebuild(&changed, &project_path, project_id, build_env)
+                        });
+                        match rebuilt {
+                            Ok(_) => {
+                                // Unwrap is safe because the server is
+                                // started after the first successful build
+                                let running = dev_server.as_ref().unwrap();
+                                running.set_files(app.files())?;
+                                running.reload();
+                                say!("App reloaded");
+                            },
+                            Err(e) => output::error(&e)
+                        }
+                    }
+                }
             },
             Command::Deploy => {
-                println!("Deploying...");
-                let version = random_version();
+                say!("Deploying...");
+                let started = Instant::now();
 
-                // Load the woz config if present or use default config
-                let conf_str = fs::read_to_string(conf_path.clone())
-                    .context(format!("Couldn't find woz config file at {}",
-                                     conf_path.clone().to_str().unwrap()))?;
-                let conf: Config = toml::from_str(&conf_str)
-                    .context("Failed to parse woz config")?;
+                let project = Project::load(&conf_path, &project_path, &home_path)?;
+                let (conf, project_id) = (&project.conf, &project.project_id);
 
-                let s3_client = upload_client::authenticated_client(&cache)
+                let target = deploy::from_config(&conf.deploy, &cache, &auth)
                     .await
-                    .context("Unable to initialize upload client")?;
+                    .context("Unable to initialize deploy target")?;
 
-                let identity_id = cache.get("identity")
-                    .context("Unable to retrieve user ID")?;
+                // All app files will be prefixed by the target's key
+                // prefix for the project
+                let key_prefix = target.key_prefix(project_id);
 
-                let ProjectId(project_id) = conf.project_id.clone();
-                let mut out_path = home_path.clone();
-                out_path.push(&project_id);
-                out_path.push("pkg");
-                fs::create_dir_all(&out_path).context("Failed to make pkg directory")?;
-
-                // All app files will be prefixed in the s3 bucket by the user's
-                // cognito identity ID and project_id
-                let key_prefix = format!("{}/{}", &identity_id, &project_id);
-
-                let mut wasm_path = project_path.clone();
-                wasm_path.push(conf.wasm_path.clone());
-
-                let url = conf.project_url.clone().unwrap_or(format!(
-                    "{}://{}/{}/{}/index.html",
-                    SCHEME,
-                    NETLOC,
-                    identity_id,
-                    project_id
-                ));
+                let url = conf.project_url.clone()
+                    .unwrap_or_else(|| target.url(project_id));
 
                 // Build the app with all the components
-                let landing_page_cmpnt = LandingPageComponent::new(
-                    &conf,
-                    &url,
-                    &handlebars
-                );
-                let wasm_cmpnt = WasmComponent::new(wasm_path, &out_path);
-                let pwa_cmpnt = PwaComponent::new(
-                    &conf,
-                    &url,
-                    &handlebars,
-                    &version
-                );
-                let icon_cmpnt = IconComponent::new(&conf);
-                let splashscreen_cmpnt = SplashscreenComponent::new(&conf);
-
-                let build_env = &conf.env.to_owned().unwrap_or(Environment::Development);
-                let mut app = AppBuilder::new();
-                app
-                    .component(&landing_page_cmpnt)
-                    .component(&wasm_cmpnt)
-                    .component(&pwa_cmpnt)
-                    .component(&icon_cmpnt)
-                    .component(&splashscreen_cmpnt)
-                    .build(&project_path, &key_prefix, &build_env)
+                let components = project.components(&url, &handlebars, None);
+                let mut app = components.builder();
+                app.build(&project_path, &key_prefix, &project.build_env())
                     .context("Failed to build app")?;
 
                 // Sets an upper bounds for the size and app that can
                 // be uploaded to prevent allowing really big files
                 // from being uploaded accidentally
-                let app_size = app.size();
-                if (app_size / 1_000_000) > MAX_APP_SIZE_MB {
-                    return Err(
-                        format_err!(
-                            "The maximum size for deploying an app is {}MB. Your app is {}MB",
-                            MAX_APP_SIZE_MB,
-                            app_size
+                if let Some(max_size_mb) = target.max_size_mb() {
+                    let app_size = app.size();
+                    if app_size > max_size_mb * 1_000_000 {
+                        return Err(
+                            format_err!(
+                                "The maximum size for deploying an app is {}MB. Your app is {}",
+                                max_size_mb,
+                                size::format_size(app_size)
+                            )
                         )
-                    )
+                    }
+                }
+                let mut report = BuildReport::new(project_id, app.version(), &url,
+                                                  &app.files(), &key_prefix,
+                                                  started.elapsed())?;
+                size::enforce_budget(conf.budget.as_ref(), &report)?;
+
+                let force = input.subcommand_matches("deploy").unwrap().is_present("force");
+                let summary = app.upload(target.as_ref(), &key_prefix, app.version(), force)
+                    .await
+                    .context("Failed to upload app")?;
+                say!("Uploaded {} files ({}), skipped {} unchanged files ({})",
+                     summary.uploaded, size::format_size(summary.uploaded_bytes),
+                     summary.skipped, size::format_size(summary.skipped_bytes));
+                say!("Released version {}", app.version());
+                say!("{}", format!("Your app is available at {}", url));
+                if output::is_json() {
+                    report.duration_ms = started.elapsed().as_millis() as u64;
+                    report.skipped = summary.skipped_files;
+                    output::emit(&report)?;
                 }
-                app.upload(s3_client).await.context("Failed to upload app")?;
-                println!("{}", format!("Your app is available at {}", url));
+            },
+            Command::Rollback => {
+                let conf = project::load_config(&conf_path)?;
+
+                let target = deploy::from_config(&conf.deploy, &cache, &auth)
+                    .await
+                    .context("Unable to initialize deploy target")?;
+                let ProjectId(project_id) = conf.project_id.clone();
+                let key_prefix = target.key_prefix(&project_id);
+
+                let mut releases = Releases::fetch(target.as_ref(), &key_prefix)
+                    .await
+                    .context("Failed to fetch releases")?;
+
+                // Defaults to the release before the current one
+                let subcommand_args = input.subcommand_matches("rollback").unwrap();
+                let version = match subcommand_args.value_of("VERSION") {
+                    Some(v) => v.to_owned(),
+                    None => releases.previous()
+                        .map(|r| r.version.clone())
+                        .ok_or_else(|| format_err!("There is no previous release to roll back to"))?
+                };
+
+                releases.activate(target.as_ref(), &key_prefix, &version)
+                    .await
+                    .context("Failed to roll back")?;
+
+                let url = conf.project_url.clone()
+                    .unwrap_or_else(|| target.url(&project_id));
+                say!("Rolled back to version {}", version);
+                say!("Your app is available at {}", url);
+            },
+            Command::Releases => {
+                let conf = project::load_config(&conf_path)?;
+
+                let target = deploy::from_config(&conf.deploy, &cache, &auth)
+                    .await
+                    .context("Unable to initialize deploy target")?;
+                let ProjectId(project_id) = conf.project_id.clone();
+
+                let releases = Releases::fetch(target.as_ref(), &target.key_prefix(&project_id))
+                    .await
+                    .context("Failed to fetch releases")?;
+
+                if releases.releases.is_empty() {
+                    say!("No releases found, run 'woz deploy' to make one");
+                }
+                // Newest first
+                for r in releases.releases.iter().rev() {
+                    let marker = if releases.current.as_ref() == Some(&r.version) { "*" } else { " " };
+                    say!("{} {}  {}", marker, r.version, release::age(r));
+                }
+            },
+            Command::Update => {
+                // This unwrap is safe because the cli preparses the
+                // subcommand before we get here
+                let subcommand_args = input.subcommand_matches("update").unwrap();
+                let index_url = subcommand_args.value_of("index")
+                    .map(String::from)
+                    .or_else(|| env::var("WOZ_UPDATE_INDEX_URL").ok())
+                    .unwrap_or_else(|| String::from(DEFAULT_UPDATE_INDEX_URL));
+
+                update::update(&index_url,
+                               crate_version!(),
+                               subcommand_args.value_of("version"),
+                               subcommand_args.is_present("check"))
+                    .await
+                    .context("Failed to update woz")?;
             }
             // Sub command parsing will print the error and exit
             // before we get to this match statement so the only way
@@ -418,16 +788,73 @@ wasm_path=\"target/wasm32-unknown-unknown/release/{}.wasm\"
     Ok(())
 }
 
+async fn resend_confirmation_code(client: &CognitoIdentityProviderClient,
+                                  username: &str) -> Result<(), Error> {
+    let destination = account::resend_confirmation_code(client, username)
+        .await
+        .context("Failed to send a new verification code")?
+        .code_delivery_details
+        .and_then(|details| details.destination)
+        .unwrap_or_else(|| String::from("your email"));
+    say!("Sent a new verification code to {}", destination);
+    Ok(())
+}
+
+/// Prompts for the code in the verification email until the signup
+/// is confirmed, sending a new code if asked or the code expired
+async fn confirm_signup(client: &CognitoIdentityProviderClient, username: &str) -> Result<(), Error> {
+    say!("Please check your inbox for an email with a code to verify your account.");
+    loop {
+        let code = match prompt::confirmation_code()? {
+            Some(code) => code,
+            None => {
+                resend_confirmation_code(client, username).await?;
+                continue;
+            }
+        };
+
+        match account::confirm_signup(client, username, &code).await {
+            Ok(_) => return Ok(()),
+            Err(RusotoError::Service(ConfirmSignUpError::CodeMismatch(_))) => {
+                say!("That code is incorrect, please try again");
+            },
+            Err(RusotoError::Service(ConfirmSignUpError::ExpiredCode(_))) => {
+                say!("That code has expired");
+                resend_confirmation_code(client, username).await?;
+            },
+            Err(e) => return Err(e).context("Failed to verify email")?
+        };
+    }
+}
+
+/// Logs in the newly signed up user and sets up the account,
+/// confirming the signup first if it hasn't been
+async fn finish_signup(id_provider_client: &CognitoIdentityProviderClient,
+                       id_client: &CognitoIdentityClient,
+                       cache: &FileCache,
+                       username: &str,
+                       password: &str) -> Result<(), Error> {
+    loop {
+        match account::setup(id_provider_client, id_client, cache, username, password).await {
+            Ok(()) => break,
+            Err(WozError::EmailNotVerified(_)) => {
+                confirm_signup(id_provider_client, username).await?;
+            },
+            Err(e) => return Err(e).context("Failed to set up account")?
+        };
+    }
+
+    cache.remove(account::PENDING_SIGNUP_KEY)
+        .context("Failed to remove signup from cache")?;
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
     run().await
         .map_err(|e| {
-            println!("{}\n{}", e,
-                     e.iter_causes()
-                     .map(|f| format!("Caused by: {}", f))
-                     .collect::<Vec<String>>()
-                     .join("\n"));
-            std::process::exit(1)
+            output::error(&e);
+            std::process::exit(error::exit_code(&e))
         })
         .ok();
     std::process::exit(0)
@@ -0,0 +1,183 @@
+//! Output for people or, with `--output json`, for other programs.
+//! In json mode stdout only holds the result of the command so
+//! messages meant for people are written to stderr instead.
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use failure::Error;
+use serde::Serialize;
+
+use crate::error;
+use crate::file_upload::FileUpload;
+use crate::deploy::manifest::relative_name;
+
+
+/// Shown in place of passwords, tokens and keys in debug output
+pub const REDACTED: &str = "<redacted>";
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether results are written as json
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Prints a message for people, to stderr in json mode and stdout
+/// otherwise.
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::output::is_json() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    }
+}
+
+/// Returns where prompts and other terminal output should go
+pub fn human() -> Box<dyn Write> {
+    if is_json() {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    }
+}
+
+/// Writes the result of a command to stdout as a single line of json
+pub fn emit<T: Serialize>(value: &T) -> Result<(), Error> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    serde_json::to_writer(&mut stdout, value)?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Prints the error and its causes
+pub fn error(e: &Error) {
+    if is_json() {
+        emit(&ErrorReport::new(e)).ok();
+    } else {
+        println!("{}\n{}", e,
+                 e.iter_causes()
+                 .map(|f| format!("Caused by: {}", f))
+                 .collect::<Vec<String>>()
+                 .join("\n"));
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorReport {
+    error: String,
+    causes: Vec<String>,
+    exit_code: i32,
+}
+
+impl ErrorReport {
+    fn new(e: &Error) -> Self {
+        Self {
+            error: e.to_string(),
+            causes: e.iter_causes().map(|c| c.to_string()).collect(),
+            exit_code: error::exit_code(e),
+        }
+    }
+}
+
+/// A file in the app bundle
+#[derive(Debug, Serialize, PartialEq)]
+pub struct FileReport {
+    pub name: String,
+    pub mimetype: String,
+    pub size: usize,
+    pub gzip_size: usize,
+}
+
+/// The result of building or deploying an app
+#[derive(Debug, Serialize)]
+pub struct BuildReport {
+    pub project_id: String,
+    pub version: String,
+    pub url: String,
+    pub files: Vec<FileReport>,
+    pub total_size: usize,
+    pub total_gzip_size: usize,
+    pub duration_ms: u64,
+    /// Files left unchanged since the previous deploy
+    pub skipped: Vec<String>,
+}
+
+impl BuildReport {
+    /// Returns the report for the files with names relative to
+    /// `prefix`
+    pub fn new(project_id: &str, version: &str, url: &str, files: &[FileUpload],
+               prefix: &str, duration: Duration) -> Result<Self, Error> {
+        let mut reports = Vec::new();
+        for f in files.iter() {
+            reports.push(FileReport {
+                name: relative_name(&f.filename, prefix).to_owned(),
+                mimetype: f.mimetype.clone(),
+                size: f.bytes.len(),
+                gzip_size: f.gzip()?.len(),
+            });
+        }
+        Ok(Self {
+            project_id: project_id.to_owned(),
+            version: version.to_owned(),
+            url: url.to_owned(),
+            total_size: reports.iter().map(|f| f.size).sum(),
+            total_gzip_size: reports.iter().map(|f| f.gzip_size).sum(),
+            files: reports,
+            duration_ms: duration.as_millis() as u64,
+            skipped: Vec::new(),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod output_tests {
+    use super::*;
+
+    #[test]
+    fn build_report_works() {
+        let files = vec![
+            FileUpload::new(String::from("proj/index.html"),
+                            String::from("text/html"),
+                            b"<html></html>".repeat(100)),
+            FileUpload::new(String::from("proj/app/app.wasm"),
+                            String::from("application/wasm"),
+                            vec![0, 97, 115, 109]),
+        ];
+        let report = BuildReport::new("myproj", "abc", "https://woz.sh/myproj/index.html",
+                                      &files, "proj", Duration::from_millis(1500)).unwrap();
+
+        assert_eq!(report.files[0].name, "index.html");
+        assert_eq!(report.files[0].size, 1300);
+        assert!(report.files[0].gzip_size < report.files[0].size);
+        assert_eq!(report.files[1].name, "app/app.wasm");
+        assert_eq!(report.total_size, 1304);
+        assert_eq!(report.total_gzip_size,
+                   report.files[0].gzip_size + report.files[1].gzip_size);
+
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(value["duration_ms"], 1500);
+        assert_eq!(value["files"][1]["mimetype"], "application/wasm");
+        assert_eq!(value["skipped"], json!([]));
+    }
+
+    #[test]
+    fn error_report_works() {
+        let error: Error = error::WozError::Build(String::from("wasm-bindgen failed")).into();
+        let value = serde_json::to_value(ErrorReport::new(&error)).unwrap();
+        assert_eq!(value, json!({
+            "error": "wasm-bindgen failed",
+            "causes": [],
+            "exit_code": 6
+        }));
+    }
+}
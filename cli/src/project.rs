@@ -0,0 +1,113 @@
+//! Loads the project config and puts together the components the
+//! app is built from, shared by build, serve and deploy.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use failure::Error;
+use failure::ResultExt;
+use handlebars::Handlebars;
+
+use crate::builder::AppBuilder;
+use crate::components::icon::IconComponent;
+use crate::components::landing_page::LandingPageComponent;
+use crate::components::pwa::PwaComponent;
+use crate::components::splashscreen::SplashscreenComponent;
+use crate::components::wasm::WasmComponent;
+use crate::config::{Config, Environment, ProjectId};
+use crate::error::WozError;
+use crate::images::ImageCache;
+
+
+/// Reads the woz config at `conf_path`
+pub fn load_config(conf_path: &Path) -> Result<Config, Error> {
+    let conf_str = fs::read_to_string(conf_path)
+        .context(WozError::Config(format!("Couldn't find woz config file at {}",
+                                          conf_path.display())))?;
+    let conf: Config = toml::from_str(&conf_str)
+        .context(WozError::Config(String::from("Failed to parse woz config")))?;
+    Ok(conf)
+}
+
+/// A project and its config along with where its build output goes
+pub struct Project {
+    pub conf: Config,
+    pub project_id: String,
+    pub project_path: PathBuf,
+    /// Directory the app bundle and intermediate files are written to
+    pub out_path: PathBuf,
+    image_cache: ImageCache,
+}
+
+impl Project {
+    /// Loads the config and makes the output directory in the woz
+    /// home directory
+    pub fn load(conf_path: &Path, project_path: &Path, home_path: &Path) -> Result<Self, Error> {
+        let conf = load_config(conf_path)?;
+        let ProjectId(project_id) = conf.project_id.clone();
+        let out_path = home_path.join(&project_id).join("pkg");
+        fs::create_dir_all(&out_path).context("Failed to make pkg directory")?;
+        let image_cache = ImageCache::new(home_path.join(&project_id).join("images"));
+
+        Ok(Self {
+            conf,
+            project_id,
+            project_path: project_path.to_path_buf(),
+            out_path,
+            image_cache,
+        })
+    }
+
+    pub fn build_env(&self) -> Environment {
+        self.conf.env.to_owned().unwrap_or(Environment::Development)
+    }
+
+    /// Returns every component of the app. `url` is where the app
+    /// can be opened and `live_reload_path` is passed on to the PWA
+    /// component when serving locally.
+    pub fn components<'a>(&'a self, url: &'a str, templates: &'a Handlebars,
+                          live_reload_path: Option<&'a str>) -> AppComponents<'a> {
+        let mut pwa = PwaComponent::new(&self.conf, url, templates);
+        if let Some(path) = live_reload_path {
+            pwa = pwa.live_reload(path);
+        }
+
+        AppComponents {
+            conf: &self.conf,
+            landing_page: LandingPageComponent::new(&self.conf, url, templates),
+            wasm: WasmComponent::new(&self.conf,
+                                     &self.project_path,
+                                     self.project_path.join(&self.conf.wasm_path),
+                                     &self.out_path,
+                                     templates),
+            pwa,
+            icon: IconComponent::new(&self.conf, &self.image_cache),
+            splashscreen: SplashscreenComponent::new(&self.conf, &self.image_cache),
+        }
+    }
+}
+
+pub struct AppComponents<'a> {
+    conf: &'a Config,
+    landing_page: LandingPageComponent<'a>,
+    wasm: WasmComponent<'a>,
+    pwa: PwaComponent<'a>,
+    icon: IconComponent<'a>,
+    splashscreen: SplashscreenComponent<'a>,
+}
+
+impl<'a> AppComponents<'a> {
+    /// Returns a builder with every component added and the build
+    /// settings from the config
+    pub fn builder(&'a self) -> AppBuilder<'a> {
+        let mut app = AppBuilder::new();
+        app
+            .component(&self.landing_page)
+            .component(&self.wasm)
+            .component(&self.pwa)
+            .component(&self.icon)
+            .component(&self.splashscreen)
+            .include_git_commit(self.conf.version_git_commit.unwrap_or(false))
+            .cargo_build(self.conf.lib.unwrap_or_default().uses_cargo_build());
+        app
+    }
+}
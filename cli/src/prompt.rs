@@ -1,85 +1,225 @@
-use std::io::{stdin, stdout, Write};
+use std::env;
+use std::fmt;
+use std::io::{self, stdin, Write};
+use failure::{Error, Fail};
 use termion::input::TermRead;
-use termion::event::Key;
 
+use crate::error::WozError;
+use crate::output;
 
-fn username_password() -> (String, String) {
-    let stdout = stdout();
-    let mut stdout = stdout.lock();
-    let stdin = stdin();
-    let mut stdin = stdin.lock();
 
-    stdout.write_all(b"Username: ").unwrap();
-    stdout.flush().expect("Error");
-    let username = stdin.read_line().expect("Fail").unwrap().to_lowercase();
+/// Environment variables used to log in without prompting
+pub const USERNAME_VAR: &str = "WOZ_USERNAME";
+pub const PASSWORD_VAR: &str = "WOZ_PASSWORD";
 
-    stdout.write_all(b"Password: ").unwrap();
-    stdout.flush().expect("Error");
-    let password_input = stdin.read_passwd(&mut stdout);
+/// Returned instead of prompting when woz is run with
+/// `--non-interactive` or without a terminal
+#[derive(Debug)]
+pub struct InteractionRequired {
+    pub action: String,
+    pub hint: String,
+}
 
-    if let Ok(Some(password)) = password_input {
-        stdout.write_all(b"\n").unwrap();
-        (username, password)
-    } else {
-        stdout.write_all(b"Error\n").unwrap();
-        panic!("Failed to get password");
+impl InteractionRequired {
+    pub fn new(action: &str, hint: &str) -> Self {
+        Self { action: action.to_owned(), hint: hint.to_owned() }
     }
 }
 
-fn email() -> String {
-    let stdout = stdout();
-    let mut stdout = stdout.lock();
-    let stdin = stdin();
-    let mut stdin = stdin.lock();
+impl fmt::Display for InteractionRequired {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} requires input but woz is running non-interactively. {}",
+               self.action, self.hint)
+    }
+}
+
+impl Fail for InteractionRequired {}
 
-    stdout.write_all(b"Email: ").unwrap();
-    stdout.flush().expect("Error");
-    stdin.read_line().expect("Fail").unwrap().to_lowercase()
+
+fn username_password() -> Result<(String, String), WozError> {
+    let username = username()?;
+    let password = read_secret("Password: ")?;
+    Ok((username, password))
 }
 
-#[derive(Debug, Clone)]
+pub fn email() -> Result<String, WozError> {
+    Ok(read_line("Email: ")?.to_lowercase())
+}
+
+#[derive(Clone)]
 pub struct SignupValues {
     pub email: String,
     pub username: String,
     pub password: String,
 }
 
-pub fn signup() -> SignupValues {
-    println!("Please enter the following information");
-    let (username, password) = username_password();
-    let email = email();
+impl fmt::Debug for SignupValues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SignupValues")
+            .field("email", &self.email)
+            .field("username", &self.username)
+            .field("password", &output::REDACTED)
+            .finish()
+    }
+}
+
+pub fn signup() -> Result<SignupValues, WozError> {
+    say!("Please enter the following information");
+    let (username, password) = username_password()?;
+    let email = email()?;
 
-    SignupValues { email, username, password }
+    Ok(SignupValues { email, username, password })
 }
 
 pub struct Credentials {
     pub username: String,
     pub password: String,
+    /// True when the user typed in the credentials so a failed login
+    /// can be retried
+    pub interactive: bool,
+}
+
+pub fn login() -> Result<Credentials, WozError> {
+    say!("Please login to continue");
+    let (username, password) = username_password()?;
+
+    Ok(Credentials { username, password, interactive: true })
+}
+
+/// Reads the username and password from the first two lines of
+/// `input`
+fn read_credentials(input: impl io::BufRead) -> Result<Credentials, Error> {
+    let mut lines = input.lines();
+    let mut next_line = || lines.next()
+        .and_then(|line| line.ok())
+        .map(|line| line.trim_end().to_owned())
+        .filter(|line| !line.is_empty());
+    match (next_line(), next_line()) {
+        (Some(username), Some(password)) => Ok(
+            Credentials { username: username.to_lowercase(), password, interactive: false }
+        ),
+        _ => Err(WozError::Auth(
+            String::from("Expected the username and password on separate lines of stdin")
+        ).into())
+    }
+}
+
+/// Returns the credentials to log in with from `WOZ_USERNAME` and
+/// `WOZ_PASSWORD`, from stdin if `from_stdin` is set, or by prompting
+/// the user if there is a terminal and `non_interactive` is false.
+/// Stdin is only read when asked to, as in CI it can be a pipe that
+/// is never closed.
+pub fn credentials(non_interactive: bool, from_stdin: bool) -> Result<Credentials, Error> {
+    if let (Ok(username), Ok(password)) = (env::var(USERNAME_VAR), env::var(PASSWORD_VAR)) {
+        return Ok(Credentials { username: username.to_lowercase(), password, interactive: false });
+    }
+
+    if from_stdin {
+        return read_credentials(stdin().lock());
+    }
+
+    if non_interactive || !termion::is_tty(&stdin()) {
+        return Err(InteractionRequired::new(
+            "Logging in",
+            "Set WOZ_USERNAME and WOZ_PASSWORD, WOZ_REFRESH_TOKEN or --token-file, or pipe the username and password to stdin with --credentials-stdin"
+        ).into());
+    }
+
+    Ok(login()?)
+}
+
+/// Asks for the code from the verification email. Returns None if
+/// the user wants a new code to be sent.
+pub fn confirmation_code() -> Result<Option<String>, WozError> {
+    let code = read_line("Verification code (leave empty to send a new one): ")?;
+    if code.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(code))
+    }
+}
+
+fn prompt_error(e: io::Error) -> WozError {
+    WozError::Auth(format!("Failed to read input: {}", e))
 }
 
-pub fn login() -> Credentials {
-    print!("Please login to continue: ");
-    let (username, password) = username_password();
+fn write_prompt(out: &mut dyn Write, text: &str) -> Result<(), WozError> {
+    out.write_all(text.as_bytes())
+        .and_then(|_| out.flush())
+        .map_err(prompt_error)
+}
+
+/// Reads a line without echoing it. Fails if stdin is closed, such
+/// as with Ctrl-D, before anything is entered.
+fn read_secret(label: &str) -> Result<String, WozError> {
+    let mut out = output::human();
+    let stdin = stdin();
+    let mut stdin = stdin.lock();
 
-    Credentials { username, password }
+    write_prompt(&mut out, label)?;
+    let password = stdin.read_passwd(&mut out).map_err(prompt_error)?;
+    write_prompt(&mut out, "\n")?;
+    password.ok_or_else(|| WozError::Auth(String::from("No password was entered")))
 }
 
-pub fn is_email_verified() -> bool {
-    let stdout = stdout();
-    let mut stdout = stdout.lock();
+/// Reads a trimmed line. Fails if stdin is closed before anything is
+/// entered.
+fn read_line(label: &str) -> Result<String, WozError> {
+    let mut out = output::human();
     let stdin = stdin();
-    let stdin = stdin.lock();
+    let mut stdin = stdin.lock();
+
+    write_prompt(&mut out, label)?;
+    let line = stdin.read_line().map_err(prompt_error)?;
+    line.map(|line| line.trim().to_owned())
+        .ok_or_else(|| WozError::Auth(String::from("No input was entered")))
+}
+
+pub fn password(username: &str) -> Result<String, WozError> {
+    read_secret(&format!("Password for {}: ", username))
+}
+
+pub fn current_password() -> Result<String, WozError> {
+    read_secret("Current password: ")
+}
+
+/// Asks for a new password twice until both match
+pub fn new_password() -> Result<String, WozError> {
+    loop {
+        let password = read_secret("New password: ")?;
+        if password == read_secret("Repeat new password: ")? {
+            return Ok(password);
+        }
+        say!("Passwords don't match, please try again");
+    }
+}
+
+pub fn username() -> Result<String, WozError> {
+    Ok(read_line("Username: ")?.to_lowercase())
+}
+
+pub fn code() -> Result<String, WozError> {
+    read_line("Code from the email: ")
+}
+
+/// Returns true if the user types `expected` to confirm
+pub fn confirm(question: &str, expected: &str) -> Result<bool, WozError> {
+    Ok(read_line(&format!("{} Type '{}' to confirm: ", question, expected))? == expected)
+}
+
 
-    stdout.write_all(b"Have you clicked the link in the verification email? [y/n] ")
-        .unwrap();
-    stdout.flush().expect("Error");
+#[cfg(test)]
+mod prompt_tests {
+    use super::*;
 
-    let c = stdin.keys().next().unwrap();
-    stdout.flush().expect("Error");
+    #[test]
+    fn read_credentials_works() {
+        let creds = read_credentials(&b"Alex\nsecret\n"[..]).unwrap();
+        assert_eq!(creds.username, "alex");
+        assert_eq!(creds.password, "secret");
+        assert!(!creds.interactive);
 
-    match c.unwrap() {
-        Key::Char('y') => true,
-        Key::Char('n') => false,
-        _ => {println!("Sorry didn't catch that"); false},
+        assert!(read_credentials(&b"alex\n"[..]).is_err());
+        assert!(read_credentials(&b""[..]).is_err());
     }
 }
@@ -0,0 +1,223 @@
+//! A local development server that serves the app bundle from memory.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, RwLock};
+
+use failure::Error;
+use failure::ResultExt;
+use futures::stream::StreamExt;
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, LOCATION};
+use hyper::service::{make_service_fn, service_fn};
+use tokio::sync::broadcast;
+
+use crate::file_upload::FileUpload;
+
+
+/// Path of the server-sent events stream that tells open browsers to
+/// reload after a rebuild
+pub const LIVE_RELOAD_PATH: &str = "/__woz/reload";
+
+struct ServedFile {
+    mimetype: String,
+    bytes: Vec<u8>,
+    gzipped: Vec<u8>,
+}
+
+/// Files keyed by their request path without the leading slash
+type Files = HashMap<String, ServedFile>;
+
+/// Returns the IP address of this machine on the local network so
+/// other devices can reach the server. No packets are sent, connecting
+/// a UDP socket only selects the outbound interface.
+pub fn lan_ip() -> IpAddr {
+    UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect("8.8.8.8:80")?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+fn accepts_gzip(req: &Request<Body>) -> bool {
+    req.headers().get(ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("gzip"))
+}
+
+fn respond(files: &Files, root: &str, req: &Request<Body>) -> Response<Body> {
+    let mut path = req.uri().path().trim_start_matches('/').to_owned();
+    if path.is_empty() {
+        return Response::builder()
+            .status(StatusCode::FOUND)
+            .header(LOCATION, format!("/{}/index.html", root))
+            .body(Body::empty())
+            .unwrap();
+    }
+    if path.ends_with('/') {
+        path.push_str("index.html");
+    }
+
+    match files.get(&path) {
+        Some(file) => {
+            let builder = Response::builder()
+                .header(CONTENT_TYPE, file.mimetype.as_str())
+                .header(CACHE_CONTROL, "no-cache");
+            if accepts_gzip(req) {
+                builder
+                    .header(CONTENT_ENCODING, "gzip")
+                    .body(Body::from(file.gzipped.clone()))
+            } else {
+                builder.body(Body::from(file.bytes.clone()))
+            }.unwrap()
+        },
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not found"))
+            .unwrap()
+    }
+}
+
+/// Serves the app bundle from memory. Files can be swapped out while
+/// the server is running and connected browsers told to reload.
+#[derive(Clone)]
+pub struct DevServer {
+    root: String,
+    files: Arc<RwLock<Files>>,
+    reload_tx: broadcast::Sender<()>,
+}
+
+impl DevServer {
+    /// Files are expected to be prefixed by `root` which is also
+    /// where requests to `/` are redirected.
+    pub fn new(root: &str) -> Self {
+        let (reload_tx, _) = broadcast::channel(16);
+        Self {
+            root: root.to_owned(),
+            files: Arc::new(RwLock::new(HashMap::new())),
+            reload_tx,
+        }
+    }
+
+    /// Replaces all of the files being served
+    pub fn set_files(&self, uploads: Vec<FileUpload>) -> Result<(), Error> {
+        let mut files = HashMap::new();
+        for upload in uploads.into_iter() {
+            let gzipped = upload.gzip().context("Failed to gzip file")?;
+            let FileUpload {filename, mimetype, bytes} = upload;
+            files.insert(filename, ServedFile {mimetype, bytes, gzipped});
+        }
+        *self.files.write().unwrap() = files;
+        Ok(())
+    }
+
+    /// Tells all connected browsers to reload the page
+    pub fn reload(&self) {
+        // Sending only fails if no browsers are connected
+        self.reload_tx.send(()).ok();
+    }
+
+    fn reload_stream(&self) -> Response<Body> {
+        let events = self.reload_tx.subscribe()
+            .map(|_| Ok::<_, Infallible>("data: reload\n\n"));
+        Response::builder()
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(CACHE_CONTROL, "no-cache")
+            .body(Body::wrap_stream(events))
+            .unwrap()
+    }
+
+    fn handle(&self, req: &Request<Body>) -> Response<Body> {
+        if req.uri().path() == LIVE_RELOAD_PATH {
+            return self.reload_stream();
+        }
+        respond(&self.files.read().unwrap(), &self.root, req)
+    }
+
+    /// Serves the app on `addr` until the process is stopped
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), Error> {
+        let make_service = make_service_fn(move |_conn| {
+            let server = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let resp = server.handle(&req);
+                    async move { Ok::<_, Infallible>(resp) }
+                }))
+            }
+        });
+
+        Server::try_bind(&addr)
+            .context(format!("Failed to bind to {}", addr))?
+            .serve(make_service)
+            .await
+            .context("Server error")?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+
+    fn test_server() -> DevServer {
+        let server = DevServer::new("test");
+        server.set_files(vec![
+            FileUpload::new(
+                String::from("test/app/index.html"),
+                String::from("text/html"),
+                b"<html></html>".to_vec()
+            )
+        ]).unwrap();
+        server
+    }
+
+    #[test]
+    fn handle_serves_files() {
+        let server = test_server();
+
+        let req = Request::get("/test/app/index.html")
+            .header(ACCEPT_ENCODING, "gzip, deflate")
+            .body(Body::empty())
+            .unwrap();
+        let resp = server.handle(&req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/html");
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+
+        let req = Request::get("/test/app/").body(Body::empty()).unwrap();
+        let resp = server.handle(&req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn handle_redirects_and_not_found() {
+        let server = test_server();
+
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let resp = server.handle(&req);
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(resp.headers()[LOCATION], "/test/index.html");
+
+        let req = Request::get("/missing.js").body(Body::empty()).unwrap();
+        let resp = server.handle(&req);
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn reload_sends_event() {
+        let server = test_server();
+
+        let req = Request::get(LIVE_RELOAD_PATH).body(Body::empty()).unwrap();
+        let resp = server.handle(&req);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/event-stream");
+
+        server.reload();
+        let mut body = resp.into_body();
+        let event = body.next().await.unwrap().unwrap();
+        assert_eq!(&event[..], b"data: reload\n\n");
+    }
+}
@@ -0,0 +1,230 @@
+//! Reports the size of the app bundle and checks it against the
+//! budgets in the config.
+use failure::Error;
+use failure::ResultExt;
+use regex::Regex;
+
+use crate::config::BudgetConfig;
+use crate::error::WozError;
+use crate::output::{BuildReport, FileReport};
+
+
+/// Returns the number of bytes for a size such as `500kB`, `1.5MB`
+/// or `2048`. Units are powers of 1000 to match the sizes reported.
+pub fn parse_size(size: &str) -> Result<usize, Error> {
+    let size = size.trim();
+    let split = size.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number.parse()
+        .map_err(|_| format_err!("Invalid size {}, use a size like 500kB or 2MB", size))?;
+    let multiplier = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "kb" => 1e3,
+        "mb" => 1e6,
+        "gb" => 1e9,
+        _ => return Err(format_err!("Unknown unit in size {}, use B, kB, MB or GB", size))
+    };
+    Ok((number * multiplier).round() as usize)
+}
+
+/// Returns the size in bytes in a human readable form such as
+/// `1.50 MB`
+pub fn format_size(bytes: usize) -> String {
+    let bytes_f = bytes as f64;
+    if bytes_f >= 1e6 {
+        format!("{:.2} MB", bytes_f / 1e6)
+    } else if bytes_f >= 1e3 {
+        format!("{:.2} kB", bytes_f / 1e3)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+/// Returns a regex for a glob where `*` matches within a directory
+/// and `**` matches across directories
+fn glob_regex(glob: &str) -> Result<Regex, Error> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no directories at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    pattern.push_str("(.*/)?");
+                } else {
+                    pattern.push_str(".*");
+                }
+            },
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Ok(Regex::new(&pattern).context(format!("Invalid budget glob {}", glob))?)
+}
+
+fn measured(budget: &BudgetConfig, file: &FileReport) -> usize {
+    if budget.gzip { file.gzip_size } else { file.size }
+}
+
+/// Returns a message for every limit the app goes over
+pub fn check_budget(budget: &BudgetConfig, report: &BuildReport) -> Result<Vec<String>, Error> {
+    let mut over = Vec::new();
+    let mut check = |what: String, size: usize, limit: &str| -> Result<(), Error> {
+        let limit = parse_size(limit).context(WozError::Config(format!("Invalid budget for {}", what)))?;
+        if size > limit {
+            over.push(format!("{} is {}, {} over the budget of {}",
+                              what, format_size(size), format_size(size - limit), format_size(limit)));
+        }
+        Ok(())
+    };
+
+    if let Some(limit) = &budget.total {
+        let total = report.files.iter().map(|f| measured(budget, f)).sum();
+        check(String::from("The app"), total, limit)?;
+    }
+    if let Some(limit) = &budget.wasm {
+        let wasm = report.files.iter()
+            .filter(|f| f.mimetype == "application/wasm")
+            .map(|f| measured(budget, f))
+            .sum();
+        check(String::from("WebAssembly"), wasm, limit)?;
+    }
+    for (glob, limit) in budget.files.iter() {
+        let regex = glob_regex(glob).context(WozError::Config(String::from("Invalid budget")))?;
+        for f in report.files.iter().filter(|f| regex.is_match(&f.name)) {
+            check(format!("{} ({})", f.name, glob), measured(budget, f), limit)?;
+        }
+    }
+    Ok(over)
+}
+
+/// Fails if the app goes over any of the limits in the budget
+pub fn enforce_budget(budget: Option<&BudgetConfig>, report: &BuildReport) -> Result<(), Error> {
+    let over = match budget {
+        Some(budget) => check_budget(budget, report)?,
+        None => return Ok(())
+    };
+    if over.is_empty() {
+        return Ok(());
+    }
+    let kind = if budget.is_some_and(|b| b.gzip) { "gzip size" } else { "size" };
+    Err(WozError::Build(format!("The app is over its {} budget:\n  {}", kind, over.join("\n  "))).into())
+}
+
+/// Returns a one line summary of the app's size
+pub fn summary(report: &BuildReport) -> String {
+    format!("{} files totalling {}, {} gzipped",
+            report.files.len(),
+            format_size(report.total_size),
+            format_size(report.total_gzip_size))
+}
+
+/// Prints every file with the largest first
+pub fn print_table(report: &BuildReport) {
+    let mut files: Vec<&FileReport> = report.files.iter().collect();
+    files.sort_by(|a, b| b.size.cmp(&a.size).then(a.name.cmp(&b.name)));
+    let width = files.iter().map(|f| f.name.len()).max().unwrap_or(0).max(5);
+
+    say!("{:width$}  {:>10}  {:>10}", "File", "Size", "Gzipped", width = width);
+    for f in files {
+        say!("{:width$}  {:>10}  {:>10}", f.name, format_size(f.size), format_size(f.gzip_size),
+             width = width);
+    }
+    say!("{:width$}  {:>10}  {:>10}", "Total", format_size(report.total_size),
+         format_size(report.total_gzip_size), width = width);
+}
+
+
+#[cfg(test)]
+mod size_tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn file(name: &str, mimetype: &str, size: usize) -> FileReport {
+        FileReport {
+            name: name.to_owned(),
+            mimetype: mimetype.to_owned(),
+            size,
+            gzip_size: size / 2,
+        }
+    }
+
+    fn report(files: Vec<FileReport>) -> BuildReport {
+        BuildReport {
+            project_id: String::from("myapp"),
+            version: String::from("abc"),
+            url: String::from("https://woz.sh/myapp/index.html"),
+            total_size: files.iter().map(|f| f.size).sum(),
+            total_gzip_size: files.iter().map(|f| f.gzip_size).sum(),
+            files,
+            duration_ms: 0,
+            skipped: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parse_size_works() {
+        assert_eq!(parse_size("2048").unwrap(), 2048);
+        assert_eq!(parse_size("500kB").unwrap(), 500_000);
+        assert_eq!(parse_size("1.5 MB").unwrap(), 1_500_000);
+        assert_eq!(parse_size("1gb").unwrap(), 1_000_000_000);
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("5 parsecs").is_err());
+    }
+
+    #[test]
+    fn format_size_works() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1_500), "1.50 kB");
+        assert_eq!(format_size(20_400_000), "20.40 MB");
+    }
+
+    #[test]
+    fn glob_regex_works() {
+        let regex = glob_regex("app/img/**/*.png").unwrap();
+        assert!(regex.is_match("app/img/icons/homescreen_48x48.png"));
+        assert!(regex.is_match("app/img/splash.png"));
+        assert!(!regex.is_match("app/app.wasm"));
+
+        let regex = glob_regex("app/*.js").unwrap();
+        assert!(regex.is_match("app/app.js"));
+        assert!(!regex.is_match("app/lib/app.js"));
+    }
+
+    #[test]
+    fn check_budget_works() {
+        let report = report(vec![
+            file("index.html", "text/html", 1_000),
+            file("app/app.wasm", "application/wasm", 150_000),
+            file("app/img/icon.png", "image/png", 20_000),
+        ]);
+        let mut files = BTreeMap::new();
+        files.insert(String::from("app/img/*.png"), String::from("10kB"));
+        let budget = BudgetConfig {
+            total: Some(String::from("1MB")),
+            wasm: Some(String::from("100kB")),
+            files,
+            gzip: false,
+        };
+
+        let over = check_budget(&budget, &report).unwrap();
+        assert_eq!(over, vec![
+            "WebAssembly is 150.00 kB, 50.00 kB over the budget of 100.00 kB",
+            "app/img/icon.png (app/img/*.png) is 20.00 kB, 10.00 kB over the budget of 10.00 kB",
+        ]);
+        assert!(enforce_budget(Some(&budget), &report).is_err());
+
+        // Gzip sizes are half of the raw sizes in this report
+        let budget = BudgetConfig { gzip: true, ..budget };
+        assert!(check_budget(&budget, &report).unwrap().is_empty());
+        assert!(enforce_budget(Some(&budget), &report).is_ok());
+        assert!(enforce_budget(None, &report).is_ok());
+
+        let budget = BudgetConfig { total: Some(String::from("lots")), ..Default::default() };
+        assert!(check_budget(&budget, &report).is_err());
+    }
+}
@@ -1,11 +1,14 @@
 use failure::Error;
 use handlebars::Handlebars;
 
+use crate::config::Config;
+
 
 const LANDING_PAGE_INDEX_TEMPLATE: &str = include_str!("templates/landing_page/index.html");
 const APP_INDEX_TEMPLATE: &str = include_str!("templates/app/index.html");
 const MANIFEST_TEMPLATE: &str = include_str!("templates/app/manifest.json");
 const SERVICE_WORKER_JS_TEMPLATE: &str = include_str!("templates/app/serviceworker.js");
+const RAW_LOADER_JS_TEMPLATE: &str = include_str!("templates/app/loader.js");
 
 pub fn load_templates() -> Result<Handlebars<'static>, Error> {
     let mut handlebars = Handlebars::new();
@@ -14,24 +17,129 @@ pub fn load_templates() -> Result<Handlebars<'static>, Error> {
     handlebars.register_template_string("app_index", APP_INDEX_TEMPLATE)?;
     handlebars.register_template_string("manifest", MANIFEST_TEMPLATE)?;
     handlebars.register_template_string("sw.js", SERVICE_WORKER_JS_TEMPLATE)?;
+    handlebars.register_template_string("loader.js", RAW_LOADER_JS_TEMPLATE)?;
     Ok(handlebars)
 }
 
+/// Returns the context shared by the app's templates: the app's
+/// details from the config, its URL and the build version as
+/// `{{version}}`, plus the template's own `values`
+pub fn page_context(conf: &Config, url: &str, version: &str,
+                    values: serde_json::Value) -> serde_json::Value {
+    let mut context = json!({
+        "name": conf.name,
+        "author": conf.author,
+        "description": conf.description,
+        "url": url,
+        "version": version,
+    });
+    if let serde_json::Value::Object(values) = values {
+        for (key, value) in values {
+            context[key.as_str()] = value;
+        }
+    }
+    context
+}
+
+/// Returns the context for rendering the app's index.html with the
+/// keys in `overrides` replaced
+#[cfg(test)]
+fn index_context(overrides: serde_json::Value) -> serde_json::Value {
+    let mut context = json!({
+        "name": "Test App",
+        "author": "Alex Kehayias",
+        "description": "Description here",
+        "url": "http://localhost",
+        "version": "abc123",
+        "asset_path": "./",
+        "manifest_path": "./manifest.json",
+        "app_js_path": "./app.js",
+        "sw_js_path": "./sw.js",
+        "wasm_path": "./app.wasm",
+        "bg_color": "#000000",
+        "live_reload_path": null,
+        "js_module": false,
+        "self_starting": false,
+        "entry": "render",
+        "mount_id": "app",
+    });
+    for (key, value) in overrides.as_object().expect("Overrides must be an object") {
+        context[key] = value.clone();
+    }
+    context
+}
+
 #[test]
 fn test_index_templates() {
+    let loader = load_templates().expect("Failed to load templates");
+    let res = loader.render("app_index", &index_context(json!({})));
+    dbg!(res.expect("Failed to render"));
+}
+
+#[test]
+fn test_module_index_template() {
     let loader = load_templates().expect("Failed to load templates");
     let res = loader.render(
         "app_index",
-        &json!({
-            "name": "Test App",
-            "author": "Alex Kehayias",
-            "description": "Description here",
-            "url": "http://localhost",
-            "manifest_path": "./manifest.json",
-            "app_js_path": "./app.js",
-            "sw_js_path": "./sw.js",
-            "wasm_path": "./app.wasm",
-            "bg_color": "#000000",
-        }));
-    dbg!(res.expect("Failed to render"));
+        &index_context(json!({
+            "js_module": true,
+            "entry": "main",
+            "mount_id": "root",
+        }))).expect("Failed to render");
+    assert!(res.contains("<script type=\"module\">"));
+    assert!(res.contains("import init, * as app from './app.js';"));
+    assert!(res.contains("init(fetch('./app.wasm'))"));
+    assert!(res.contains(".then(app.main)"));
+    assert!(res.contains("<main id=\"root\"></main>"));
+    assert!(res.contains("<link rel=\"preload\" href=\"./app.wasm\" as=\"fetch\""));
+    assert!(!res.contains("wasm_bindgen("));
+}
+
+#[test]
+fn test_self_starting_index_template() {
+    let loader = load_templates().expect("Failed to load templates");
+    let res = loader.render(
+        "app_index",
+        &index_context(json!({
+            "self_starting": true,
+            "entry": null,
+        }))).expect("Failed to render");
+    assert!(res.contains("<script src=\"./app.js\" defer></script>"));
+    assert!(!res.contains("wasm_bindgen("));
+}
+
+#[test]
+fn test_raw_loader_template() {
+    let loader = load_templates().expect("Failed to load templates");
+    let res = loader.render("loader.js", &json!({"entry": "render"})).expect("Failed to render");
+    assert!(res.contains("new URL('app.wasm', document.currentScript.src)"));
+    assert!(res.contains("result.instance.exports.render();"));
+
+    let res = loader.render("loader.js", &json!({"entry": null})).expect("Failed to render");
+    assert!(!res.contains("exports."));
+}
+
+#[test]
+fn test_service_worker_template() {
+    let loader = load_templates().expect("Failed to load templates");
+    let res = loader.render(
+        "sw.js",
+        &page_context(&Config::default(), "http://localhost", "abc123", json!({
+            "precache_manifest": "[{\"url\":\"index.html\",\"revision\":\"123\"}]",
+            "settings": "{\"routes\":[],\"defaultRoute\":{\"strategy\":\"cache-first\"},\"offlineUrl\":null}",
+        }))).expect("Failed to render");
+    assert!(res.contains("var cacheName = 'version-abc123';"));
+    assert!(res.contains("var precacheManifest = [{\"url\":\"index.html\",\"revision\":\"123\"}];"));
+    assert!(res.contains("var settings = {\"routes\":[]"));
+}
+
+#[test]
+fn test_version_is_rendered() {
+    let loader = load_templates().expect("Failed to load templates");
+    let res = loader.render("app_index", &index_context(json!({}))).expect("Failed to render");
+    assert!(res.contains("<meta name=\"version\" content=\"abc123\">"));
+
+    let context = page_context(&Config::default(), "http://localhost", "abc123", json!({}));
+    let res = loader.render("landing_page_index", &context).expect("Failed to render");
+    assert!(res.contains("<meta name=\"version\" content=\"abc123\">"));
 }
@@ -0,0 +1,23 @@
+// Loads a wasm module built without bindings. Generated by woz.
+(function() {
+  {{!-- Relative to the script since the page can be in another directory --}}
+  var url = new URL('app.wasm', document.currentScript.src).href;
+  var imports = {};
+
+  function instantiate() {
+    return fetch(url)
+      .then(function(response) { return response.arrayBuffer(); })
+      .then(function(bytes) { return WebAssembly.instantiate(bytes, imports); });
+  }
+
+  {{!-- Streaming fails when the server doesn't send the wasm mimetype --}}
+  var loading = WebAssembly.instantiateStreaming
+    ? WebAssembly.instantiateStreaming(fetch(url), imports).catch(instantiate)
+    : instantiate();
+
+  loading
+    {{#if entry}}
+    .then(function(result) { result.instance.exports.{{ entry }}(); })
+    {{/if}}
+    .catch(console.error);
+})();
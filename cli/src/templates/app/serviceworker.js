@@ -1,42 +1,219 @@
 var cacheName = 'version-{{ version }}';
-var appShellFiles = [
-  '/index.html',
-];
+// Every file in the app shell with a hash of its contents
+var precacheManifest = {{{ precache_manifest }}};
+// Caching strategies for requests outside of the app shell from the
+// [service_worker] section of woz.toml
+var settings = {{{ settings }}};
 
-// On install, download files to the cache
+function absoluteUrl(url) {
+  return new URL(url, self.location.href).href;
+}
+
+// Precached files are stored by URL and hash so unchanged files can be
+// reused from the cache of an earlier version
+function cacheKey(entry) {
+  return absoluteUrl(entry.url) + '?__woz_revision=' + entry.revision;
+}
+
+var cacheKeys = {};
+precacheManifest.forEach(function(entry) {
+  cacheKeys[absoluteUrl(entry.url)] = cacheKey(entry);
+});
+cacheKeys[absoluteUrl('./')] = cacheKeys[absoluteUrl('index.html')];
+
+settings.routes.forEach(function(route, i) {
+  try {
+    route.regex = new RegExp(route.pattern);
+  } catch (err) {
+    // A route the browser can't parse never matches
+    console.error('Invalid service worker route pattern ' + route.pattern, err);
+    route.regex = /(?!)/;
+  }
+  route.cacheName = cacheName + '-route-' + i;
+});
+settings.defaultRoute.cacheName = cacheName + '-runtime';
+
+function findRoute(url) {
+  for (var i = 0; i < settings.routes.length; i++) {
+    if (settings.routes[i].regex.test(url)) {
+      return settings.routes[i];
+    }
+  }
+  return settings.defaultRoute;
+}
+
+// Responses are stamped with the time they were cached so max-age can
+// be enforced. Opaque responses can't be read so they never expire.
+var CACHED_AT_HEADER = 'x-woz-cached-at';
+
+function isFresh(route, response) {
+  var cachedAt = response.headers.get(CACHED_AT_HEADER);
+  if (!route.maxAgeSeconds || !cachedAt) {
+    return true;
+  }
+  return Date.now() - parseInt(cachedAt, 10) < route.maxAgeSeconds * 1000;
+}
+
+function trimCache(cache, maxEntries) {
+  if (!maxEntries) {
+    return Promise.resolve();
+  }
+  return cache.keys().then(function(keys) {
+    // Keys are in insertion order so the oldest are removed first
+    return Promise.all(keys.slice(0, Math.max(keys.length - maxEntries, 0)).map(function(key) {
+      return cache.delete(key);
+    }));
+  });
+}
+
+function store(route, request, response) {
+  if (!response || (!response.ok && response.type !== 'opaque')) {
+    return Promise.resolve();
+  }
+  var stamped = Promise.resolve(response);
+  if (response.type !== 'opaque') {
+    stamped = response.blob().then(function(body) {
+      var headers = new Headers(response.headers);
+      headers.set(CACHED_AT_HEADER, Date.now().toString());
+      return new Response(body, {
+        status: response.status,
+        statusText: response.statusText,
+        headers: headers
+      });
+    });
+  }
+  return Promise.all([caches.open(route.cacheName), stamped]).then(function(results) {
+    var cache = results[0];
+    return cache.put(request, results[1]).then(function() {
+      return trimCache(cache, route.maxEntries);
+    });
+  });
+}
+
+function fromCache(route, request) {
+  return caches.open(route.cacheName).then(function(cache) {
+    return cache.match(request);
+  });
+}
+
+function fromNetwork(route, request) {
+  return fetch(request).then(function(response) {
+    console.log('[Service Worker] Caching new resource: ' + request.url);
+    store(route, request, response.clone());
+    return response;
+  });
+}
+
+var strategies = {
+  'cache-first': function(route, request) {
+    return fromCache(route, request).then(function(cached) {
+      if (cached && isFresh(route, cached)) {
+        return cached;
+      }
+      return fromNetwork(route, request).catch(function(err) {
+        // A stale response is better than none when offline
+        if (cached) {
+          return cached;
+        }
+        throw err;
+      });
+    });
+  },
+  'network-first': function(route, request) {
+    return fromNetwork(route, request).catch(function(err) {
+      return fromCache(route, request).then(function(cached) {
+        if (cached && isFresh(route, cached)) {
+          return cached;
+        }
+        throw err;
+      });
+    });
+  },
+  'stale-while-revalidate': function(route, request, e) {
+    return fromCache(route, request).then(function(cached) {
+      var update = fromNetwork(route, request);
+      if (cached && isFresh(route, cached)) {
+        e.waitUntil(update.catch(function() {}));
+        return cached;
+      }
+      return update;
+    });
+  },
+  'network-only': function(route, request) {
+    return fetch(request);
+  }
+};
+
+function offlineFallback(request, err) {
+  if (request.mode === 'navigate' && settings.offlineUrl) {
+    return caches.match(cacheKeys[absoluteUrl(settings.offlineUrl)]).then(function(page) {
+      if (page) {
+        return page;
+      }
+      throw err;
+    });
+  }
+  throw err;
+}
+
+// On install, download the app shell to the cache
 self.addEventListener('install', function(e) {
   console.log('[Service Worker] Installing');
   e.waitUntil(
     caches.open(cacheName).then(function(cache) {
-      console.log('[Service Worker] Caching app shell and content');
-      return cache.addAll(appShellFiles);
+      console.log('[Service Worker] Caching app shell');
+      return Promise.all(precacheManifest.map(function(entry) {
+        var key = cacheKey(entry);
+        return caches.match(key).then(function(cached) {
+          return cached || fetch(entry.url, {cache: 'no-cache'}).then(function(response) {
+            if (!response.ok) {
+              throw new Error('Failed to fetch ' + entry.url);
+            }
+            return response;
+          });
+        }).then(function(response) {
+          return cache.put(key, response);
+        });
+      }));
     })
   );
 });
 
-// Offline uses cache
 self.addEventListener('fetch', function(e) {
+  // Only reads can be cached, everything else goes to the network
+  if (e.request.method !== 'GET') {
+    return;
+  }
+
+  var url = e.request.url.split('#')[0];
+  console.log('[Service Worker] Fetching resource: ' + url);
+
+  // The app shell is always served from the cache
+  var key = cacheKeys[url];
+  if (key) {
+    e.respondWith(
+      caches.match(key).then(function(r) {
+        return r || fetch(e.request);
+      })
+    );
+    return;
+  }
+
+  var route = findRoute(url);
   e.respondWith(
-    caches.match(e.request).then(function(r) {
-      console.log('[Service Worker] Fetching resource: ' + e.request.url);
-      return r || fetch(e.request).then(function(response) {
-        return caches.open(cacheName).then(function(cache) {
-          console.log('[Service Worker] Caching new resource: ' + e.request.url);
-          cache.put(e.request, response.clone());
-          return response;
-        });
-      });
+    strategies[route.strategy](route, e.request, e).catch(function(err) {
+      return offlineFallback(e.request, err);
     })
   );
 });
 
-// Clear out existing cache
+// Clear out caches from earlier versions
 self.addEventListener('activate', function(e) {
   console.log('[Service Worker] Activating');
   e.waitUntil(
     caches.keys().then(function(keyList) {
       return Promise.all(keyList.map(function(key) {
-        if (cacheName.indexOf(key) === -1) {
+        if (key !== cacheName && key.indexOf(cacheName + '-') !== 0) {
           console.log('[Service Worker] Deleting cache: ' + key);
           return caches.delete(key);
         }
@@ -0,0 +1,378 @@
+//! Self-update for the woz binary. Releases are described by a JSON
+//! index that maps each version to a download per target triple:
+//!
+//! {
+//!   "latest": "1.5.0",
+//!   "releases": {
+//!     "1.5.0": {
+//!       "x86_64-apple-darwin": {
+//!         "url": "https://example.com/woz-v1.5.0-x86_64-apple-darwin.tar.gz",
+//!         "sha256": "<hex encoded sha256 of the download>"
+//!       }
+//!     }
+//!   }
+//! }
+//!
+//! The index and artifact URLs can be http(s) URLs or local file
+//! paths. Relative artifact URLs are resolved against the location of
+//! the index.
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use failure::Error;
+use failure::ResultExt;
+use flate2::read::GzDecoder;
+use hyper::{Body, Client, Uri};
+use hyper::header::LOCATION;
+use hyper_tls::HttpsConnector;
+
+use crate::config::TARGET;
+use crate::file_upload::sha256_hex;
+
+
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, Deserialize)]
+pub struct Artifact {
+    pub url: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReleaseIndex {
+    pub latest: String,
+    pub releases: HashMap<String, HashMap<String, Artifact>>,
+}
+
+impl ReleaseIndex {
+    /// Returns the artifact for the given version and target triple
+    pub fn artifact(&self, version: &str, target: &str) -> Result<&Artifact, Error> {
+        let targets = self.releases.get(version)
+            .ok_or_else(|| format_err!("Version {} was not found in the release index", version))?;
+        targets.get(target)
+            .ok_or_else(|| format_err!("Version {} has no release for target {}", version, target))
+    }
+}
+
+/// Parses a version string like `1.4.0` or `v1.4.0` into a tuple
+/// that can be compared.
+fn parse_version(version: &str) -> Result<(u64, u64, u64), Error> {
+    let parts = version.trim_start_matches('v')
+        .split('.')
+        .map(|p| p.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()
+        .context(format!("Invalid version {}", version))?;
+
+    match parts.as_slice() {
+        [major, minor, patch] => Ok((*major, *minor, *patch)),
+        _ => Err(format_err!("Invalid version {}, expected MAJOR.MINOR.PATCH", version))
+    }
+}
+
+/// Returns true if `candidate` is a newer version than `current`
+fn is_newer(candidate: &str, current: &str) -> Result<bool, Error> {
+    Ok(parse_version(candidate)? > parse_version(current)?)
+}
+
+/// Resolves an artifact URL relative to the location of the index
+fn resolve(index_location: &str, url: &str) -> String {
+    if url.contains("://") || Path::new(url).is_absolute() {
+        return url.to_owned();
+    }
+    match index_location.rfind('/') {
+        Some(i) => format!("{}/{}", &index_location[..i], url),
+        None => url.to_owned()
+    }
+}
+
+/// Resolves the `Location` of a redirect from `uri`, which can be an
+/// absolute URL or relative to the scheme, host or path of `uri`
+fn redirect_uri(uri: &Uri, location: &str) -> Result<Uri, Error> {
+    let scheme = uri.scheme_str().unwrap_or("https");
+    let authority = uri.authority().map(|a| a.as_str()).unwrap_or("");
+    let resolved = if location.contains("://") {
+        location.to_owned()
+    } else if location.starts_with("//") {
+        format!("{}:{}", scheme, location)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, location)
+    } else {
+        let path = uri.path();
+        let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+        format!("{}://{}{}{}", scheme, authority, dir, location)
+    };
+    Ok(resolved.parse::<Uri>().context(format!("Invalid redirect URL {}", location))?)
+}
+
+async fn fetch_http(url: &str) -> Result<Vec<u8>, Error> {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let mut uri = url.parse::<Uri>().context(format!("Invalid URL {}", url))?;
+
+    // Release downloads are usually served via a redirect so follow
+    // a limited number of them
+    for _ in 0..MAX_REDIRECTS {
+        let resp = client.get(uri.clone()).await
+            .context(format!("Request to {} failed", uri))?;
+
+        if resp.status().is_redirection() {
+            let location = resp.headers().get(LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .ok_or_else(|| format_err!("Redirect from {} is missing a location", uri))?;
+            uri = redirect_uri(&uri, location)?;
+            continue;
+        }
+
+        if !resp.status().is_success() {
+            return Err(format_err!("Request to {} failed with status {}", uri, resp.status()));
+        }
+
+        let bytes = hyper::body::to_bytes(resp.into_body()).await
+            .context(format!("Failed to read response from {}", uri))?;
+        return Ok(bytes.to_vec());
+    }
+
+    Err(format_err!("Too many redirects fetching {}", url))
+}
+
+/// Fetches the contents of an http(s) URL or local file path
+async fn fetch(location: &str) -> Result<Vec<u8>, Error> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        fetch_http(location).await
+    } else {
+        let path = location.trim_start_matches("file://");
+        Ok(fs::read(path).context(format!("Failed to read {}", path))?)
+    }
+}
+
+pub async fn fetch_index(location: &str) -> Result<ReleaseIndex, Error> {
+    let bytes = fetch(location).await.context("Failed to fetch release index")?;
+    let index = serde_json::from_slice(&bytes).context("Failed to parse release index")?;
+    Ok(index)
+}
+
+fn verify_checksum(bytes: &[u8], expected: &str) -> Result<(), Error> {
+    let actual = sha256_hex(bytes);
+
+    if actual != expected.to_lowercase() {
+        return Err(format_err!("Checksum mismatch, expected {} but got {}", expected, actual));
+    }
+    Ok(())
+}
+
+/// Returns the woz binary from the downloaded release. Releases are
+/// either a gzipped tarball containing the binary or the binary itself.
+fn extract_binary(bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+    // Gzip magic number
+    if !bytes.starts_with(&[0x1f, 0x8b]) {
+        return Ok(bytes);
+    }
+
+    let mut archive = tar::Archive::new(GzDecoder::new(bytes.as_slice()));
+    for entry in archive.entries().context("Failed to read release archive")? {
+        let mut entry = entry.context("Failed to read release archive entry")?;
+        let is_binary = entry.path()
+            .context("Invalid path in release archive")?
+            .file_name()
+            .is_some_and(|name| name == "woz" || name == "woz.exe");
+
+        if is_binary {
+            let mut buffer = Vec::new();
+            entry.read_to_end(&mut buffer).context("Failed to extract woz from release archive")?;
+            return Ok(buffer);
+        }
+    }
+
+    Err(format_err!("Release archive does not contain a woz binary"))
+}
+
+/// Replaces the executable at `exe_path` with `bytes`. The new binary
+/// is written next to the existing one and then renamed over it so
+/// the replacement is atomic.
+fn replace_executable(exe_path: &PathBuf, bytes: &[u8]) -> Result<(), Error> {
+    let dir = exe_path.parent()
+        .ok_or_else(|| format_err!("Unable to find the directory of the woz executable"))?;
+    let mut tmp_path = dir.to_path_buf();
+    tmp_path.push(".woz-update");
+
+    fs::write(&tmp_path, bytes).context("Failed to write new woz binary")?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o755))
+            .context("Failed to make new woz binary executable")?;
+    }
+
+    if let Err(e) = fs::rename(&tmp_path, exe_path) {
+        fs::remove_file(&tmp_path).ok();
+        return Err(format_err!("Failed to replace woz binary: {}", e));
+    }
+    Ok(())
+}
+
+/// Checks the release index for a newer version of woz and installs
+/// it in place of the running executable. When `version` is given
+/// that version is installed regardless of whether it is newer. With
+/// `check_only` nothing is installed.
+pub async fn update(index_location: &str,
+                    current_version: &str,
+                    version: Option<&str>,
+                    check_only: bool) -> Result<(), Error> {
+    let index = fetch_index(index_location).await?;
+
+    if check_only {
+        if is_newer(&index.latest, current_version)? {
+            say!("A new version of woz is available: {} (currently {}). Run 'woz update' to install it.",
+                     index.latest, current_version);
+        } else {
+            say!("woz is up to date ({})", current_version);
+        }
+        return Ok(());
+    }
+
+    let target_version = match version {
+        Some(v) => v.trim_start_matches('v'),
+        None => {
+            if !is_newer(&index.latest, current_version)? {
+                say!("woz is up to date ({})", current_version);
+                return Ok(());
+            }
+            &index.latest
+        }
+    };
+
+    let artifact = index.artifact(target_version, TARGET)?;
+    let url = resolve(index_location, &artifact.url);
+    say!("Downloading woz {} from {}", target_version, url);
+
+    let download = fetch(&url).await.context("Failed to download release")?;
+    verify_checksum(&download, &artifact.sha256)
+        .context("Downloaded release failed verification")?;
+    let binary = extract_binary(download)?;
+
+    let exe_path = env::current_exe().context("Unable to find the woz executable")?;
+    replace_executable(&exe_path, &binary)?;
+
+    say!("Updated woz from {} to {}", current_version, target_version);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod update_tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    #[test]
+    fn parse_version_works() {
+        assert_eq!(parse_version("1.4.0").unwrap(), (1, 4, 0));
+        assert_eq!(parse_version("v10.0.12").unwrap(), (10, 0, 12));
+        assert!(parse_version("1.4").is_err());
+        assert!(parse_version("latest").is_err());
+    }
+
+    #[test]
+    fn is_newer_works() {
+        assert!(is_newer("1.10.0", "1.9.3").unwrap());
+        assert!(!is_newer("1.4.0", "1.4.0").unwrap());
+        assert!(!is_newer("0.9.0", "1.4.0").unwrap());
+    }
+
+    #[test]
+    fn resolve_works() {
+        assert_eq!(resolve("https://woz.sh/bin/releases.json", "woz.tar.gz"),
+                   "https://woz.sh/bin/woz.tar.gz");
+        assert_eq!(resolve("/tmp/releases.json", "woz.tar.gz"), "/tmp/woz.tar.gz");
+        assert_eq!(resolve("/tmp/releases.json", "https://example.com/woz"),
+                   "https://example.com/woz");
+    }
+
+    #[test]
+    fn redirect_uri_works() {
+        let uri: Uri = "https://woz.sh/bin/latest?target=x86_64".parse().unwrap();
+        assert_eq!(redirect_uri(&uri, "https://cdn.example.com/woz").unwrap(),
+                   "https://cdn.example.com/woz");
+        assert_eq!(redirect_uri(&uri, "//cdn.example.com/woz").unwrap(), "https://cdn.example.com/woz");
+        assert_eq!(redirect_uri(&uri, "/releases/download/woz.tar.gz?sig=1").unwrap(),
+                   "https://woz.sh/releases/download/woz.tar.gz?sig=1");
+        assert_eq!(redirect_uri(&uri, "woz.tar.gz").unwrap(), "https://woz.sh/bin/woz.tar.gz");
+    }
+
+    #[tokio::test]
+    async fn fetch_follows_relative_redirects() {
+        use hyper::{Response, Server, StatusCode};
+        use hyper::service::{make_service_fn, service_fn};
+        use std::convert::Infallible;
+
+        let make_service = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|req: hyper::Request<Body>| async move {
+                let resp = match req.uri().path() {
+                    "/bin/latest" => Response::builder()
+                        .status(StatusCode::FOUND)
+                        .header(LOCATION, "/releases/download/woz.tar.gz")
+                        .body(Body::empty()),
+                    "/releases/download/woz.tar.gz" => Response::builder().body(Body::from("woz")),
+                    _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty())
+                };
+                Ok::<_, Infallible>(resp.unwrap())
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/bin/latest", server.local_addr());
+        tokio::spawn(server);
+
+        assert_eq!(fetch(&url).await.unwrap(), b"woz");
+    }
+
+    #[tokio::test]
+    async fn fetch_index_works() {
+        let index = fetch_index("test_data/release_index.json").await.unwrap();
+        assert_eq!(index.latest, "1.5.0");
+        let artifact = index.artifact("1.5.0", "x86_64-apple-darwin").unwrap();
+        assert_eq!(artifact.url, "woz-v1.5.0-x86_64-apple-darwin.tar.gz");
+        assert!(index.artifact("1.5.0", "unknown-target").is_err());
+        assert!(index.artifact("0.0.1", "x86_64-apple-darwin").is_err());
+    }
+
+    #[test]
+    fn verify_checksum_works() {
+        let sha = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(verify_checksum(b"hello", sha).is_ok());
+        assert!(verify_checksum(b"hello", &sha.to_uppercase()).is_ok());
+        assert!(verify_checksum(b"goodbye", sha).is_err());
+    }
+
+    #[test]
+    fn extract_binary_works() {
+        let contents = b"#!/bin/sh\necho woz".to_vec();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        builder.append_data(&mut header, "woz", contents.as_slice()).unwrap();
+        let archive = builder.into_inner().unwrap().finish().unwrap();
+
+        assert_eq!(extract_binary(archive).unwrap(), contents);
+        assert_eq!(extract_binary(contents.clone()).unwrap(), contents);
+    }
+
+    #[test]
+    fn replace_executable_works() {
+        let mut dir = env::temp_dir();
+        dir.push("woz-replace-executable-test");
+        fs::create_dir_all(&dir).unwrap();
+        let mut exe_path = dir.clone();
+        exe_path.push("woz");
+        fs::write(&exe_path, b"old").unwrap();
+
+        replace_executable(&exe_path, b"new").unwrap();
+
+        assert_eq!(fs::read(&exe_path).unwrap(), b"new");
+    }
+}
@@ -1,4 +1,10 @@
 //! The cli used to interact with the woz service.
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use failure::Error;
 use failure::ResultExt;
 use rusoto_core::Region;
@@ -10,103 +16,234 @@ use rusoto_s3::*;
 use crate::cache::FileCache;
 use crate::prompt;
 use crate::account;
+use crate::error::WozError;
+use crate::output::REDACTED;
 
 
-/// Attempt to get a refresh_token token, prompting the user to log in if
-/// refresh token is expired and stores it locally.
-async fn ensure_refresh_token(cache: &FileCache, client: &CognitoIdentityProviderClient) -> String {
-    let mut token = None;
+/// Environment variable holding a refresh token to use instead of the
+/// one cached by `woz setup`
+pub const REFRESH_TOKEN_VAR: &str = "WOZ_REFRESH_TOKEN";
+pub const TOKEN_FILE_VAR: &str = "WOZ_TOKEN_FILE";
 
-    while let None = token {
-        match cache.get_encrypted("refresh_token") {
-            Ok(t) => token = Some(t),
-            Err(_) => {
-                let creds = prompt::login();
-                let resp = account::login(&client, &creds.username, &creds.password).await;
+/// Cached AWS credentials are replaced this long before they expire
+/// so they don't expire in the middle of a deploy
+const CREDENTIALS_EXPIRY_MARGIN_SECS: i64 = 5 * 60;
 
-                // Keep retrying login until successful
-                if resp.is_err() {
-                    continue
-                };
+/// How to get credentials when the user needs to be logged in
+#[derive(Debug, Clone, Default)]
+pub struct AuthOptions {
+    /// Return an error instead of prompting for credentials
+    pub non_interactive: bool,
+    /// File containing a refresh token
+    pub token_file: Option<PathBuf>,
+    /// Read the username and password from stdin
+    pub credentials_stdin: bool,
+}
 
-                let t = resp.unwrap().authentication_result
-                    .expect("Failed")
-                    .refresh_token
-                    .expect("Missing refresh token");
+/// Returns the refresh token from `WOZ_REFRESH_TOKEN` or the token
+/// file if either was supplied.
+pub fn supplied_refresh_token(opts: &AuthOptions) -> Result<Option<String>, Error> {
+    if let Ok(token) = env::var(REFRESH_TOKEN_VAR) {
+        return Ok(Some(token.trim().to_owned()));
+    }
 
-                cache.set_encrypted("refresh_token", t.as_bytes().to_vec())
-                    .expect("Failed to cache refresh token");
+    let token_file = opts.token_file.clone()
+        .or_else(|| env::var_os(TOKEN_FILE_VAR).map(PathBuf::from));
+    match token_file {
+        Some(path) => {
+            let token = fs::read_to_string(&path)
+                .context(WozError::Auth(format!("Failed to read token file {}", path.display())))?;
+            Ok(Some(token.trim().to_owned()))
+        },
+        None => Ok(None)
+    }
+}
 
-                token = Some(t)
-            }
+/// Logs the user in and caches the refresh token. Only retries a
+/// failed login when the user is typing in credentials, otherwise
+/// bad credentials would loop forever.
+async fn login(cache: &FileCache, client: &CognitoIdentityProviderClient,
+               opts: &AuthOptions) -> Result<AuthenticationResultType, Error> {
+    loop {
+        let creds = prompt::credentials(opts.non_interactive, opts.credentials_stdin)?;
+        match account::login(client, &creds.username, &creds.password).await {
+            Ok(resp) => {
+                let auth_result = resp.authentication_result
+                    .ok_or_else(|| WozError::Auth(String::from("Login did not return any tokens")))?;
+                let refresh_token = auth_result.refresh_token.as_ref()
+                    .ok_or_else(|| WozError::Auth(String::from("Missing refresh token")))?;
+                cache.set_encrypted("refresh_token", refresh_token.as_bytes().to_vec())
+                    .context("Failed to cache refresh token")?;
+                return Ok(auth_result);
+            },
+            Err(error) if creds.interactive => say!("Login failed: {}", error),
+            Err(error) => return Err(
+                WozError::from_rusoto(error, |msg| WozError::Auth(format!("Login failed: {}", msg))).into()
+            )
         }
-    };
-
-    token.unwrap()
+    }
 }
 
-async fn ensure_id_token(cache: &FileCache, id_provider_client: &CognitoIdentityProviderClient, refresh_token: &str) -> String {
-    let result = account::refresh_auth(&id_provider_client, &refresh_token).await;
-
-    match result {
-        Ok(resp) => resp
-            .authentication_result.expect("No auth result")
-            .id_token.expect("No ID token"),
-        Err(error) => {
-            println!("Getting refresh token failed: {}", error);
-            let mut id_token = None;
+/// Returns fresh tokens for the user, logging in if there is no
+/// refresh token or it has expired.
+async fn ensure_tokens(cache: &FileCache, id_provider_client: &CognitoIdentityProviderClient,
+                       opts: &AuthOptions) -> Result<AuthenticationResultType, Error> {
+    let supplied = supplied_refresh_token(opts)?;
+    let refresh_token = supplied.clone()
+        .or_else(|| cache.get_encrypted("refresh_token").ok());
 
-            while let None = id_token {
-                let creds = prompt::login();
-                let username = creds.username;
-                let password = creds.password;
+    let auth_result = match refresh_token {
+        Some(token) => match account::refresh_auth(id_provider_client, &token).await {
+            Ok(resp) => resp.authentication_result
+                .ok_or_else(|| WozError::Auth(String::from("Refreshing tokens did not return any tokens")))?,
+            // A supplied token is never replaced by logging in so a
+            // bad token fails loudly
+            Err(error) if supplied.is_some() => {
+                return Err(WozError::from_rusoto(error, |msg| WozError::Auth(
+                    format!("Failed to log in with the supplied refresh token: {}", msg)
+                )).into());
+            },
+            Err(error) => {
+                warn!("Getting refresh token failed: {}", error);
+                login(cache, id_provider_client, opts).await?
+            }
+        },
+        None => login(cache, id_provider_client, opts).await?
+    };
 
-                let result = account::login(&id_provider_client, &username, &password).await;
+    Ok(auth_result)
+}
 
-                if result.is_err() {
-                    println!("Login failed: {}", error);
-                    continue
-                };
+async fn ensure_id_token(cache: &FileCache, id_provider_client: &CognitoIdentityProviderClient,
+                         opts: &AuthOptions) -> Result<String, Error> {
+    ensure_tokens(cache, id_provider_client, opts).await?
+        .id_token
+        .ok_or_else(|| WozError::Auth(String::from("Missing ID token")).into())
+}
 
-                let resp = result.unwrap();
+/// Returns an access token for managing the user's account, logging
+/// in if needed
+pub async fn access_token(cache: &FileCache, id_provider_client: &CognitoIdentityProviderClient,
+                          opts: &AuthOptions) -> Result<String, Error> {
+    ensure_tokens(cache, id_provider_client, opts).await?
+        .access_token
+        .ok_or_else(|| WozError::Auth(String::from("Missing access token")).into())
+}
 
-                // Store the refresh token too
-                let refresh_token = resp.clone()
-                    .authentication_result.expect("Failed")
-                    .refresh_token.expect("Missing refresh token");
+/// Temporary AWS credentials for the logged in user
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct CachedCredentials {
+    access_key_id: String,
+    secret_key: String,
+    session_token: String,
+    /// Seconds since the unix epoch
+    expiration: i64,
+}
 
-                cache.set_encrypted("refresh_token", refresh_token.as_bytes().to_vec())
-                    .expect("Failed to cache refresh token");
+impl fmt::Debug for CachedCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CachedCredentials")
+            .field("access_key_id", &self.access_key_id)
+            .field("secret_key", &REDACTED)
+            .field("session_token", &REDACTED)
+            .field("expiration", &self.expiration)
+            .finish()
+    }
+}
 
-                id_token = Some(resp
-                                .authentication_result.expect("No auth result")
-                                .id_token.expect("No ID token"));
+impl CachedCredentials {
+    fn load(cache: &FileCache) -> Option<Self> {
+        cache.get_encrypted(account::AWS_CREDENTIALS_KEY).ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+    }
 
-                break
-            };
+    fn store(&self, cache: &FileCache) -> Result<(), Error> {
+        let json = serde_json::to_vec(self).context("Failed to serialize AWS credentials")?;
+        cache.set_encrypted(account::AWS_CREDENTIALS_KEY, json)
+    }
 
-            id_token.unwrap()
-        }
+    /// Returns true if the credentials are valid for long enough to
+    /// be used for a deploy
+    fn is_fresh(&self, now: i64) -> bool {
+        self.expiration - CREDENTIALS_EXPIRY_MARGIN_SECS > now
     }
 }
 
-pub async fn authenticated_client(cache: &FileCache) -> Result<S3Client, Error> {
-    let id_provider_client = account::anonymous_identity_provider_client();
-    let id_client = account::anonymous_identity_client();
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+async fn fetch_credentials(cache: &FileCache, opts: &AuthOptions) -> Result<CachedCredentials, Error> {
+    let id_provider_client = account::anonymous_identity_provider_client()?;
+    let id_client = account::anonymous_identity_client()?;
 
-    let refresh_token = ensure_refresh_token(&cache, &id_provider_client).await;
-    let identity_id = cache.get("identity").context("Unable to retrieve user ID")?;
-    let id_token = ensure_id_token(&cache, &id_provider_client, &refresh_token).await;
+    let id_token = ensure_id_token(cache, &id_provider_client, opts).await?;
+    let identity_id = match cache.get("identity") {
+        Ok(identity_id) => identity_id,
+        // Machines that never ran `woz setup`, such as CI, look up
+        // the identity using the ID token
+        Err(_) => {
+            let identity_id = account::identity_id(&id_client, &id_token).await
+                .map_err(|e| WozError::from_rusoto(e, |msg| WozError::Auth(
+                    format!("Unable to retrieve user ID: {}", msg)
+                )))?
+                .identity_id
+                .ok_or_else(|| WozError::Auth(String::from("Missing identity ID")))?;
+            cache.set("identity", identity_id.as_bytes().to_vec())
+                .context("Failed to cache user ID")?;
+            identity_id
+        }
+    };
 
     let aws_creds = account::aws_credentials(&id_client, &identity_id, &id_token).await
-        .context("Failed to fetch AWS credentials")?
-        .credentials.expect("Missing credentials");
+        .map_err(|e| WozError::from_rusoto(e, |msg| WozError::Auth(
+            format!("Failed to fetch AWS credentials: {}", msg)
+        )))?
+        .credentials
+        .ok_or_else(|| WozError::Auth(String::from("Missing credentials")))?;
+    let missing = |field: &str| WozError::Auth(format!("AWS credentials are missing the {}", field));
+
+    Ok(CachedCredentials {
+        access_key_id: aws_creds.access_key_id.ok_or_else(|| missing("access key"))?,
+        secret_key: aws_creds.secret_key.ok_or_else(|| missing("secret key"))?,
+        session_token: aws_creds.session_token.ok_or_else(|| missing("session token"))?,
+        expiration: aws_creds.expiration.ok_or_else(|| missing("expiration"))? as i64,
+    })
+}
+
+/// Returns an S3 client using temporary credentials for the logged in
+/// user. Credentials are cached until shortly before they expire so
+/// deploys in quick succession don't need to go through Cognito.
+pub async fn authenticated_client(cache: &FileCache, opts: &AuthOptions) -> Result<S3Client, Error> {
+    // Cached credentials belong to the cached login so they aren't
+    // used with a supplied refresh token
+    let use_cache = supplied_refresh_token(opts)?.is_none();
+    let cached = CachedCredentials::load(cache)
+        .filter(|creds| use_cache && creds.is_fresh(now()));
+
+    let creds = match cached {
+        Some(creds) => {
+            debug!("Using cached AWS credentials {:?}", creds);
+            creds
+        },
+        None => {
+            let creds = fetch_credentials(cache, opts).await?;
+            debug!("Fetched AWS credentials {:?}", creds);
+            if use_cache {
+                creds.store(cache).context("Failed to cache AWS credentials")?;
+            }
+            creds
+        }
+    };
 
     let creds_provider = StaticProvider::new(
-        aws_creds.access_key_id.expect("Missing access key"),
-        aws_creds.secret_key.expect("Missing secret key"),
-        Some(aws_creds.session_token.expect("Missing session token")),
-        Some(aws_creds.expiration.expect("Missing expiration") as i64)
+        creds.access_key_id,
+        creds.secret_key,
+        Some(creds.session_token),
+        Some(creds.expiration)
     );
 
     let request_dispatcher = HttpClient::new();
@@ -117,3 +254,54 @@ pub async fn authenticated_client(cache: &FileCache) -> Result<S3Client, Error>
     );
     Ok(client)
 }
+
+
+#[cfg(test)]
+mod upload_client_tests {
+    use super::*;
+
+    #[test]
+    fn supplied_refresh_token_from_file_works() {
+        let mut path = env::temp_dir();
+        path.push("woz-token-test");
+        fs::write(&path, "my-refresh-token\n").unwrap();
+
+        let opts = AuthOptions { non_interactive: true, token_file: Some(path), credentials_stdin: false };
+        assert_eq!(supplied_refresh_token(&opts).unwrap(), Some(String::from("my-refresh-token")));
+
+        let opts = AuthOptions {
+            non_interactive: true,
+            token_file: Some(PathBuf::from("/no/such/file")),
+            credentials_stdin: false
+        };
+        assert!(supplied_refresh_token(&opts).is_err());
+    }
+
+    #[test]
+    fn cached_credentials_works() {
+        let mut home_path = env::temp_dir();
+        home_path.push("woz-aws-credentials-test");
+        fs::remove_dir_all(&home_path).ok();
+        let cache = FileCache::new([0; 32], home_path);
+        assert!(CachedCredentials::load(&cache).is_none());
+
+        let creds = CachedCredentials {
+            access_key_id: String::from("access_key_123"),
+            secret_key: String::from("secret_key_123"),
+            session_token: String::from("session_token_123"),
+            expiration: 10_000,
+        };
+        creds.store(&cache).unwrap();
+        assert_eq!(CachedCredentials::load(&cache), Some(creds.clone()));
+
+        assert!(creds.is_fresh(10_000 - CREDENTIALS_EXPIRY_MARGIN_SECS - 1));
+        assert!(!creds.is_fresh(10_000 - CREDENTIALS_EXPIRY_MARGIN_SECS));
+        assert!(!creds.is_fresh(10_001));
+
+        // Secrets are left out of debug output
+        let debug = format!("{:?}", creds);
+        assert!(debug.contains("access_key_123"));
+        assert!(!debug.contains("secret_key_123"));
+        assert!(!debug.contains("session_token_123"));
+    }
+}
@@ -0,0 +1,135 @@
+//! Reads the sections of a wasm binary.
+use std::ops::Range;
+
+use failure::Error;
+
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+// Magic number followed by a 4 byte version
+pub const WASM_HEADER_LEN: usize = 8;
+// Custom sections hold names, producers, debug info and other data
+// that isn't needed to run the module
+pub const CUSTOM_SECTION_ID: u8 = 0;
+const EXPORT_SECTION_ID: u8 = 7;
+const START_SECTION_ID: u8 = 8;
+const FUNCTION_EXPORT_KIND: u8 = 0;
+
+/// Reads an unsigned LEB128 encoded integer starting at `pos`,
+/// advancing `pos` past it
+fn read_leb128_u32(bytes: &[u8], pos: &mut usize) -> Result<u32, Error> {
+    let mut result: u32 = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes.get(*pos)
+            .ok_or_else(|| format_err!("Unexpected end of wasm file"))?;
+        *pos += 1;
+        result |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err(format_err!("Invalid integer in wasm file"))
+}
+
+pub struct Section {
+    pub id: u8,
+    /// The whole section including the id and size
+    pub range: Range<usize>,
+    pub contents: Range<usize>,
+}
+
+/// Returns the sections of the module in order
+pub fn sections(bytes: &[u8]) -> Result<Vec<Section>, Error> {
+    if bytes.len() < WASM_HEADER_LEN || &bytes[..4] != WASM_MAGIC {
+        return Err(format_err!("Not a wasm file"));
+    }
+
+    let mut sections = Vec::new();
+    let mut pos = WASM_HEADER_LEN;
+    while pos < bytes.len() {
+        let start = pos;
+        let id = bytes[pos];
+        pos += 1;
+        let size = read_leb128_u32(bytes, &mut pos)? as usize;
+        let end = pos.checked_add(size)
+            .filter(|end| *end <= bytes.len())
+            .ok_or_else(|| format_err!("Section extends past the end of the wasm file"))?;
+        sections.push(Section { id, range: start..end, contents: pos..end });
+        pos = end;
+    }
+    Ok(sections)
+}
+
+/// Returns the names of the functions the module exports
+pub fn exported_functions(bytes: &[u8]) -> Result<Vec<String>, Error> {
+    let mut names = Vec::new();
+    for section in sections(bytes)?.iter().filter(|s| s.id == EXPORT_SECTION_ID) {
+        let contents = &bytes[section.contents.clone()];
+        let mut pos = 0;
+        let count = read_leb128_u32(contents, &mut pos)?;
+        for _ in 0..count {
+            let len = read_leb128_u32(contents, &mut pos)? as usize;
+            let name = contents.get(pos..pos + len)
+                .ok_or_else(|| format_err!("Export name extends past the end of the section"))?;
+            pos += len;
+            let kind = *contents.get(pos)
+                .ok_or_else(|| format_err!("Unexpected end of export section"))?;
+            pos += 1;
+            // The index of the exported item isn't needed
+            read_leb128_u32(contents, &mut pos)?;
+            if kind == FUNCTION_EXPORT_KIND {
+                names.push(String::from_utf8_lossy(name).into_owned());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Returns true if the module has a function that runs as soon as
+/// it's instantiated
+pub fn has_start_section(bytes: &[u8]) -> Result<bool, Error> {
+    Ok(sections(bytes)?.iter().any(|s| s.id == START_SECTION_ID))
+}
+
+
+#[cfg(test)]
+pub mod wasm_binary_tests {
+    use super::*;
+
+    pub fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![id, contents.len() as u8];
+        bytes.extend_from_slice(contents);
+        bytes
+    }
+
+    pub fn module(sections: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        for s in sections.iter() {
+            bytes.extend_from_slice(s);
+        }
+        bytes
+    }
+
+    #[test]
+    fn read_leb128_u32_works() {
+        let mut pos = 0;
+        assert_eq!(read_leb128_u32(&[0xe5, 0x8e, 0x26], &mut pos).unwrap(), 624_485);
+        assert_eq!(pos, 3);
+        assert!(read_leb128_u32(&[0x80], &mut 0).is_err());
+    }
+
+    #[test]
+    fn exported_functions_works() {
+        // Exports a function named render and a memory named memory
+        let exports = section(EXPORT_SECTION_ID, b"\x02\x06render\x00\x01\x06memory\x02\x00");
+        let wasm = module(&[exports]);
+        assert_eq!(exported_functions(&wasm).unwrap(), vec!["render"]);
+        assert!(!has_start_section(&wasm).unwrap());
+
+        let wasm = module(&[section(START_SECTION_ID, &[0])]);
+        assert!(exported_functions(&wasm).unwrap().is_empty());
+        assert!(has_start_section(&wasm).unwrap());
+
+        let truncated = module(&[section(EXPORT_SECTION_ID, b"\x01\x06ren")]);
+        assert!(exported_functions(&truncated).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+//! Shrinks the wasm file produced by wasm-bindgen before it's added
+//! to the app bundle.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::time::Instant;
+
+use failure::Error;
+use failure::ResultExt;
+
+use crate::config::OptimizeLevel;
+use crate::error::WozError;
+use crate::size::format_size;
+use crate::wasm_binary::{sections, CUSTOM_SECTION_ID, WASM_HEADER_LEN};
+
+
+/// Returns the module without any custom sections
+pub fn strip_custom_sections(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let sections = sections(bytes)?;
+    let mut stripped = bytes[..WASM_HEADER_LEN].to_vec();
+    for section in sections.into_iter().filter(|s| s.id != CUSTOM_SECTION_ID) {
+        stripped.extend_from_slice(&bytes[section.range]);
+    }
+    Ok(stripped)
+}
+
+/// Runs `wasm-opt` on the module, returning `None` if it isn't
+/// installed
+fn run_wasm_opt(bytes: &[u8], flag: &str, work_dir: &Path) -> Result<Option<Vec<u8>>, Error> {
+    let input = work_dir.join("app_stripped.wasm");
+    let output = work_dir.join("app_opt.wasm");
+    fs::write(&input, bytes).context("Failed to write wasm file for wasm-opt")?;
+
+    debug!("Running wasm-opt {} {}", flag, input.display());
+    let started = Instant::now();
+    let result = process::Command::new("wasm-opt")
+        .arg(flag)
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        .output();
+    let result = match result {
+        Ok(result) => result,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("wasm-opt was not found on your PATH, skipping it. Install binaryen to make the app smaller.");
+            return Ok(None);
+        },
+        Err(e) => return Err(e).context(WozError::Build(String::from("Failed to run wasm-opt")))?
+    };
+    if !result.status.success() {
+        return Err(WozError::Build(format!(
+            "wasm-opt failed: {}", String::from_utf8_lossy(&result.stderr).trim()
+        )).into());
+    }
+    info!("Optimized wasm with wasm-opt in {:.2?}", started.elapsed());
+
+    let optimized = fs::read(&output).context("Failed to read wasm-opt output")?;
+    Ok(Some(optimized))
+}
+
+/// Applies the optimizations for the level to the module. Files
+/// needed by `wasm-opt` are written to `work_dir`.
+pub fn optimize(bytes: Vec<u8>, level: OptimizeLevel, work_dir: &Path) -> Result<Vec<u8>, Error> {
+    if level == OptimizeLevel::None {
+        return Ok(bytes);
+    }
+
+    let original_size = bytes.len();
+    let mut optimized = strip_custom_sections(&bytes)
+        .context(WozError::Build(String::from("Failed to strip wasm custom sections")))?;
+    if let Some(flag) = level.wasm_opt_flag() {
+        if let Some(bytes) = run_wasm_opt(&optimized, flag, work_dir)? {
+            optimized = bytes;
+        }
+    }
+
+    say!("Optimized app.wasm from {} to {}", format_size(original_size), format_size(optimized.len()));
+    Ok(optimized)
+}
+
+
+#[cfg(test)]
+mod wasm_opt_tests {
+    use super::*;
+    use crate::wasm_binary::wasm_binary_tests::{module, section};
+
+    #[test]
+    fn strip_custom_sections_works() {
+        // A type section with one function type and custom sections
+        // around it
+        let types = section(1, &[1, 0x60, 0, 0]);
+        let name = section(0, b"\x04name\x01\x02\x03");
+        let producers = section(0, b"\x09producers\x00");
+        let wasm = module(&[name, types.clone(), producers]);
+
+        assert_eq!(strip_custom_sections(&wasm).unwrap(), module(&[types]));
+        assert!(strip_custom_sections(b"not wasm").is_err());
+        assert!(strip_custom_sections(b"wasm").is_err());
+
+        let truncated = module(&[section(1, &[1, 0x60, 0, 0])]);
+        assert!(strip_custom_sections(&truncated[..truncated.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn optimize_none_works() {
+        let wasm = module(&[section(0, b"\x04name")]);
+        assert_eq!(optimize(wasm.clone(), OptimizeLevel::None, &std::env::temp_dir()).unwrap(), wasm);
+        assert_eq!(optimize(wasm, OptimizeLevel::Strip, &std::env::temp_dir()).unwrap(), module(&[]));
+    }
+}
@@ -0,0 +1,115 @@
+//! Watches project files so the app can be rebuilt when they change.
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+use failure::Error;
+use failure::ResultExt;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+
+// Editors often write a file in several steps so wait for changes to
+// settle before reporting them
+const DEBOUNCE_MS: u64 = 200;
+
+pub struct FileWatcher {
+    // Dropping the watcher stops it so it needs to be held on to
+    _watcher: RecommendedWatcher,
+    rx: Receiver<DebouncedEvent>,
+    paths: Vec<PathBuf>,
+}
+
+impl FileWatcher {
+    /// Watches the given files and directories. Paths that don't
+    /// exist are skipped.
+    pub fn new(paths: &[PathBuf]) -> Result<Self, Error> {
+        let (tx, rx) = channel();
+        let mut watcher: RecommendedWatcher = Watcher::new(tx, Duration::from_millis(DEBOUNCE_MS))
+            .context("Failed to start file watcher")?;
+
+        let mut watched = Vec::new();
+        for path in paths.iter() {
+            let path = match fs::canonicalize(path) {
+                Ok(p) => p,
+                Err(_) => continue
+            };
+
+            // Files are watched via their parent directory because
+            // many editors save by replacing the file which would
+            // otherwise end the watch
+            let (watch_path, mode) = if path.is_dir() {
+                (path.clone(), RecursiveMode::Recursive)
+            } else {
+                (path.parent().map_or(path.clone(), |p| p.to_path_buf()),
+                 RecursiveMode::NonRecursive)
+            };
+            watcher.watch(&watch_path, mode)
+                .context(format!("Failed to watch {}", watch_path.display()))?;
+            watched.push(path);
+        }
+
+        Ok(Self { _watcher: watcher, rx, paths: watched })
+    }
+
+    /// Blocks until one or more of the watched paths change and
+    /// returns the changed paths.
+    pub fn wait(&self) -> Result<Vec<PathBuf>, Error> {
+        loop {
+            let event = self.rx.recv().context("File watcher stopped")?;
+            let mut changed = Vec::new();
+            self.collect(event, &mut changed);
+            // Pick up anything else that changed at the same time
+            while let Ok(event) = self.rx.try_recv() {
+                self.collect(event, &mut changed);
+            }
+
+            if !changed.is_empty() {
+                return Ok(changed);
+            }
+        }
+    }
+
+    fn collect(&self, event: DebouncedEvent, changed: &mut Vec<PathBuf>) {
+        let paths = match event {
+            DebouncedEvent::Create(p) |
+            DebouncedEvent::Write(p) |
+            DebouncedEvent::Remove(p) => vec![p],
+            DebouncedEvent::Rename(from, to) => vec![from, to],
+            _ => Vec::new()
+        };
+
+        for path in paths.into_iter() {
+            let is_watched = self.paths.iter().any(|w| path.starts_with(w));
+            if is_watched && !changed.contains(&path) {
+                changed.push(path);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn wait_reports_changed_files() {
+        let mut dir = env::temp_dir();
+        dir.push("woz-watch-test");
+        fs::create_dir_all(&dir).unwrap();
+        let mut watched_file = dir.clone();
+        watched_file.push("woz.toml");
+        fs::write(&watched_file, "name=\"Test\"").unwrap();
+        let mut other_file = dir.clone();
+        other_file.push("other.txt");
+
+        let watcher = FileWatcher::new(&[watched_file.clone()]).unwrap();
+        fs::write(&other_file, "ignored").unwrap();
+        fs::write(&watched_file, "name=\"Changed\"").unwrap();
+
+        let changed = watcher.wait().unwrap();
+        assert_eq!(changed, vec![fs::canonicalize(&watched_file).unwrap()]);
+    }
+}